@@ -0,0 +1,97 @@
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+
+// Numeric fields of a document, e.g. a timestamp or a popularity, that searches
+// can boost the score with. They don't take part in the id of the document, so
+// adding it again with other metadata updates it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Metadata(pub BTreeMap<String, f64>);
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, field: &str) -> Option<f64> {
+        self.0.get(field).copied()
+    }
+}
+
+impl Hash for Metadata {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+// Adjusts the score of a document by its metadata. Boosts are applied in the
+// order they are given, starting from the similarity to the query.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Boost {
+    // Halves the score every "half_life" that the timestamp in "field" is older
+    // than "now". Documents without the field keep their score.
+    Decay {
+        field: String,
+        now: f64,
+        half_life: f64,
+    },
+    // Multiplies the score by the field, which defaults to 1.
+    Weight {
+        field: String,
+    },
+    // Adds the fields multiplied by their weights. Missing fields count as 0.
+    Linear {
+        weights: BTreeMap<String, f64>,
+    },
+}
+
+impl Boost {
+    // A decay with a half life of 0, negative or not finite would turn the scores
+    // into NaN or infinity, so it's rejected.
+    pub fn decay(field: String, now: f64, half_life: f64) -> anyhow::Result<Boost> {
+        if !(half_life.is_finite() && half_life > 0.0) {
+            bail!(
+                "the half life of a decay must be a positive number, found {}",
+                half_life
+            );
+        }
+
+        Ok(Boost::Decay {
+            field,
+            now,
+            half_life,
+        })
+    }
+}
+
+// Searches with boosts rerank this many times as many candidates as they return,
+// since a boost can lift documents that are further from the query.
+pub const BOOST_CANDIDATES: usize = 10;
+
+pub fn boost(score: f32, metadata: &Metadata, boosts: &[Boost]) -> f32 {
+    let mut score = score as f64;
+
+    for boost in boosts {
+        match boost {
+            Boost::Decay {
+                field,
+                now,
+                half_life,
+            } => {
+                if let Some(timestamp) = metadata.get(field) {
+                    let age = (now - timestamp).max(0.0);
+                    score *= 0.5f64.powf(age / half_life);
+                }
+            }
+            Boost::Weight { field } => score *= metadata.get(field).unwrap_or(1.0),
+            Boost::Linear { weights } => {
+                score += weights
+                    .iter()
+                    .map(|(field, weight)| weight * metadata.get(field).unwrap_or(0.0))
+                    .sum::<f64>();
+            }
+        }
+    }
+
+    score as f32
+}
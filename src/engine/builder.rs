@@ -0,0 +1,58 @@
+use crate::EmbeddedResource;
+
+use super::engine::{build_reduced, entry, Index};
+use super::segment::Entry;
+use super::tree::IndexOptions;
+
+// Builds an index from resources that arrive in batches, e.g. read from a stream,
+// so the whole input never has to be in memory at once. Each batch is moved into
+// the builder: its embeddings are kept as they are instead of being copied.
+#[derive(Default)]
+pub struct IndexBuilder {
+    entries: Vec<Entry>,
+    options: IndexOptions,
+    progress: Option<Box<dyn FnMut(usize)>>,
+}
+
+impl IndexBuilder {
+    pub fn new() -> IndexBuilder {
+        IndexBuilder::default()
+    }
+
+    pub fn with_options(mut self, options: IndexOptions) -> anyhow::Result<IndexBuilder> {
+        options.validate()?;
+        self.options = options;
+        Ok(self)
+    }
+
+    // Called with the number of resources pushed so far after every batch.
+    pub fn on_progress(mut self, progress: impl FnMut(usize) + 'static) -> IndexBuilder {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn push(&mut self, batch: impl IntoIterator<Item = EmbeddedResource>) -> usize {
+        self.entries.extend(batch.into_iter().map(entry));
+
+        let pushed = self.entries.len();
+        if let Some(progress) = self.progress.as_mut() {
+            progress(pushed);
+        }
+
+        pushed
+    }
+
+    pub fn finish(self) -> anyhow::Result<Index> {
+        build_reduced(self.entries, &self.options)
+    }
+}
+
+impl Extend<EmbeddedResource> for IndexBuilder {
+    fn extend<T: IntoIterator<Item = EmbeddedResource>>(&mut self, batch: T) {
+        self.push(batch);
+    }
+}
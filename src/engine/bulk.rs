@@ -0,0 +1,103 @@
+use std::cmp::Ordering;
+
+// Node references with this bit set point to a leaf, the others to a stem.
+pub const LEAF: u32 = 1 << 31;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stem {
+    pub dimension: u32,
+    pub split: f32,
+    pub left: u32,
+    pub right: u32,
+}
+
+// A tree built from the whole point set at once. Every stem splits its points at
+// the median of a dimension, cycling through the dimensions of the points, so
+// leaf depths differ by at most one no matter in which order the points come.
+// Points on the left of a stem are at most the split value and points on the
+// right at least the split value, which is all searches rely on to prune the
+// tree. Stems come before their children.
+#[derive(Debug, Clone, Default)]
+pub struct Bulk {
+    pub stems: Vec<Stem>,
+    // Per leaf: first point and number of points.
+    pub leaves: Vec<(u32, u32)>,
+    // Positions of the points, reordered so that every leaf is a range of them.
+    pub order: Vec<usize>,
+    pub root: u32,
+}
+
+struct Builder<C> {
+    coordinate: C,
+    dimensions: usize,
+    bucket_size: usize,
+    stems: Vec<Stem>,
+    leaves: Vec<(u32, u32)>,
+}
+
+impl<C: Fn(usize, usize) -> f32> Builder<C> {
+    fn coordinate(&self, point: usize, dimension: usize) -> f32 {
+        (self.coordinate)(point, dimension)
+    }
+
+    fn node(&mut self, order: &mut [usize], start: usize, dimension: usize) -> u32 {
+        if order.len() <= self.bucket_size {
+            self.leaves.push((start as u32, order.len() as u32));
+            return LEAF | (self.leaves.len() - 1) as u32;
+        }
+
+        let median = order.len() / 2;
+        order.select_nth_unstable_by(median, |a, b| {
+            self.coordinate(*a, dimension)
+                .partial_cmp(&self.coordinate(*b, dimension))
+                .unwrap_or(Ordering::Equal)
+        });
+
+        let stem = self.stems.len();
+        self.stems.push(Stem {
+            dimension: dimension as u32,
+            split: self.coordinate(order[median], dimension),
+            left: 0,
+            right: 0,
+        });
+
+        let next = (dimension + 1) % self.dimensions;
+        let (left, right) = order.split_at_mut(median);
+        self.stems[stem].left = self.node(left, start, next);
+        self.stems[stem].right = self.node(right, start + median, next);
+
+        stem as u32
+    }
+}
+
+// A tree of "points" points of up to "dimensions" dimensions, whose coordinates
+// are given by "coordinate" from the position of the point and the dimension.
+// Coordinates past the end of a point are zero, like the padding added by
+// `point`. Callers check that the points fit in the u32 node references first.
+pub fn build(
+    points: usize,
+    dimensions: usize,
+    bucket_size: usize,
+    coordinate: impl Fn(usize, usize) -> f32,
+) -> Bulk {
+    let mut builder = Builder {
+        coordinate,
+        dimensions: dimensions.max(1),
+        bucket_size,
+        stems: vec![],
+        leaves: vec![],
+    };
+    let mut order: Vec<usize> = (0..points).collect();
+    let root = if points == 0 {
+        LEAF
+    } else {
+        builder.node(&mut order, 0, 0)
+    };
+
+    Bulk {
+        stems: builder.stems,
+        leaves: builder.leaves,
+        order,
+        root,
+    }
+}
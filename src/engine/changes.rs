@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::duplicates::deduplicate;
+use super::engine::{add_entries, clear, entries, get_entry, remove_documents, Document, Index};
+use super::hash;
+use super::precision::Embeddings;
+use super::segment::Chunks;
+use super::stats::dimension;
+use super::tree::IndexOptions;
+use super::validation::{accept_all, validate};
+
+// Largest version an index can be at: the largest integer a JS number holds
+// exactly, so that versions go through JS unchanged. Versions count changes one
+// at a time, so no index gets anywhere near it.
+pub const MAX_VERSION: u64 = (1 << 53) - 1;
+
+// Latest change of a document.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Change {
+    // Version at which the document was added, or added back after a removal.
+    pub created: u64,
+    pub changed: u64,
+    // The removed document, kept so that its removal can be sent to other indexes.
+    pub removed: Option<Document>,
+}
+
+// Version of the index, bumped by every add, remove and clear, and the version
+// at which each document last changed. Documents the index was built with have
+// no change and belong to version 0.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChangeLog {
+    pub version: u64,
+    // Version of the last clear. Every document changed after it.
+    pub cleared: u64,
+    // Latest removal dropped by a compaction. Changes since an earlier version
+    // can't be listed one by one anymore.
+    #[serde(default)]
+    pub truncated: u64,
+    pub changes: HashMap<u64, Change>,
+}
+
+impl ChangeLog {
+    pub fn bump(&mut self) {
+        self.version = (self.version + 1).min(MAX_VERSION);
+    }
+
+    // "existed" tells whether the document was already in the index, which is
+    // only needed for documents that haven't changed since it was built.
+    pub fn added(&mut self, id: u64, existed: bool) {
+        let version = self.version;
+        let change = self.changes.entry(id).or_insert(Change {
+            created: if existed { 0 } else { version },
+            changed: version,
+            removed: None,
+        });
+        if change.removed.take().is_some() {
+            change.created = version;
+        }
+        change.changed = version;
+    }
+
+    pub fn removed(&mut self, id: u64, document: Document) {
+        let version = self.version;
+        let change = self.changes.entry(id).or_insert(Change {
+            created: 0,
+            changed: version,
+            removed: None,
+        });
+        change.changed = version;
+        change.removed = Some(document);
+    }
+
+    pub fn cleared(&mut self) {
+        self.cleared = self.version;
+        self.changes.clear();
+    }
+
+    // Drops the documents kept for their removal, so that the log only grows
+    // with the documents of the index.
+    pub fn truncate(&mut self) {
+        let mut truncated = self.truncated;
+        self.changes.retain(|_, change| match change.removed {
+            Some(_) => {
+                truncated = truncated.max(change.changed);
+                false
+            }
+            None => true,
+        });
+        self.truncated = truncated;
+    }
+}
+
+// Changes of an index between two of its versions, to be applied to another index.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Delta {
+    pub since: u64,
+    pub version: u64,
+    // Whether the index was cleared in between. The other changes come after it.
+    pub cleared: bool,
+    pub added: Vec<(Document, Chunks)>,
+    pub updated: Vec<(Document, Chunks)>,
+    pub removed: Vec<Document>,
+}
+
+// Documents added, updated or removed after the index was at version "since".
+// If a compaction dropped removals made since then, the changes are a clear
+// followed by every document of the index.
+pub fn export_changes_since(index: &Index, since: u64) -> Delta {
+    let log = &index.changes;
+    let mut delta = Delta {
+        since,
+        version: log.version,
+        cleared: log.cleared > since,
+        ..Delta::default()
+    };
+
+    if log.truncated > since {
+        let mut documents: Vec<(u64, &Document, &Embeddings)> = entries(index).collect();
+        documents.sort_by_key(|(id, _, _)| *id);
+        delta.cleared = true;
+        delta.added = documents
+            .into_iter()
+            .map(|(_, document, embeddings)| {
+                (document.to_owned(), embeddings.decode().into_owned())
+            })
+            .collect();
+
+        return delta;
+    }
+
+    let mut changes: Vec<(&u64, &Change)> = log
+        .changes
+        .iter()
+        .filter(|(_, change)| change.changed > since)
+        .collect();
+    changes.sort_by_key(|(id, change)| (change.changed, **id));
+
+    for (id, change) in changes {
+        match &change.removed {
+            // Documents added and removed in between were never seen.
+            Some(_) if change.created > since => {}
+            Some(document) => delta.removed.push(document.to_owned()),
+            None => {
+                if let Some((document, chunks)) = get_entry(index, id) {
+                    let entry = (document.to_owned(), chunks.decode().into_owned());
+                    if change.created > since || delta.cleared {
+                        delta.added.push(entry);
+                    } else {
+                        delta.updated.push(entry);
+                    }
+                }
+            }
+        }
+    }
+
+    delta
+}
+
+// Applies the changes exported from another index. Every change is made through
+// add, remove and clear, so it bumps the version of this index as well.
+//
+// The embeddings of a delta are the ones stored by the other index, so they are
+// already reduced: they are checked against the dimension this index stores
+// rather than reduced again. The delta is rejected as a whole if any of them is
+// invalid, before anything changes.
+pub fn apply_changes(index: &mut Index, delta: &Delta) -> anyhow::Result<()> {
+    let entries = delta
+        .added
+        .iter()
+        .chain(&delta.updated)
+        .map(|(document, chunks)| (hash(document), document.to_owned(), chunks.to_owned()))
+        .collect();
+    let stored = IndexOptions {
+        reduction: None,
+        ..index.options
+    };
+    let expected = match index.options.reduction {
+        Some(reduction) => Some(reduction.dims),
+        None if delta.cleared => None,
+        None => Some(dimension(index)).filter(|dimension| *dimension > 0),
+    };
+    let (entries, rejected) = validate(&stored, expected, entries);
+    accept_all(&rejected)?;
+
+    if delta.cleared {
+        clear(index);
+    }
+    remove_documents(index, &delta.removed);
+
+    let entries = deduplicate(index, entries)?;
+    add_entries(index, entries)
+}
@@ -0,0 +1,172 @@
+use anyhow::bail;
+
+use super::engine::{entries, Document, Index};
+use super::parallel;
+use super::precision::Embeddings;
+use super::random::Random;
+use super::segment::{mean_chunk, point, squared_euclidean, Point};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cluster<'a> {
+    pub centroid: Vec<f32>,
+    // Members ordered by id.
+    pub members: Vec<&'a Document>,
+    // Member closest to the centroid, absent for an empty cluster.
+    pub nearest: Option<&'a Document>,
+}
+
+// Centroids found by k-means, and the centroid each point was assigned to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KMeans {
+    pub centroids: Vec<Point>,
+    pub assignments: Vec<usize>,
+}
+
+// Groups the documents of the index into k clusters with k-means. Each document
+// is clustered by the mean of its chunks. The result only depends on the seed
+// and the documents, not on the order they are stored in.
+pub fn cluster(
+    index: &Index,
+    k: usize,
+    iterations: usize,
+    seed: u64,
+) -> anyhow::Result<Vec<Cluster<'_>>> {
+    if k == 0 {
+        bail!("the number of clusters must be at least 1");
+    }
+
+    let mut documents: Vec<(u64, &Document, &Embeddings)> = entries(index).collect();
+    documents.sort_by(|a, b| a.1.id.cmp(&b.1.id).then(a.0.cmp(&b.0)));
+    let means: Vec<Vec<f32>> = documents
+        .iter()
+        .map(|(_, _, embeddings)| mean_chunk(&embeddings.decode()))
+        .collect();
+    let dimension = means.iter().map(Vec::len).max().unwrap_or(0);
+    let points: Vec<Point> = means.iter().map(|mean| point(mean)).collect();
+
+    let KMeans {
+        centroids,
+        assignments,
+    } = kmeans(&points, k, iterations, seed);
+    let mut clusters: Vec<Cluster> = centroids
+        .iter()
+        .map(|centroid| Cluster {
+            centroid: centroid[..dimension].to_vec(),
+            members: vec![],
+            nearest: None,
+        })
+        .collect();
+
+    let mut nearest = vec![f32::INFINITY; clusters.len()];
+    for (i, (_, document, _)) in documents.iter().enumerate() {
+        let cluster = &mut clusters[assignments[i]];
+        cluster.members.push(document);
+
+        let distance = squared_euclidean(&points[i], &centroids[assignments[i]]);
+        if distance < nearest[assignments[i]] {
+            nearest[assignments[i]] = distance;
+            cluster.nearest = Some(document);
+        }
+    }
+
+    Ok(clusters)
+}
+
+// Lloyd's algorithm seeded with k-means++, for at most "iterations" rounds or
+// until the assignments stop changing. Fewer than k points give one centroid
+// per point.
+pub fn kmeans(points: &[Point], k: usize, iterations: usize, seed: u64) -> KMeans {
+    let mut centroids = initial_centroids(points, k, &mut Random::new(seed));
+    let mut assignments = assign(points, &centroids);
+    for _ in 0..iterations {
+        centroids = update(points, &assignments, &centroids);
+
+        let next = assign(points, &centroids);
+        let converged = next == assignments;
+        assignments = next;
+        if converged {
+            break;
+        }
+    }
+
+    KMeans {
+        centroids,
+        assignments,
+    }
+}
+
+// k-means++: the first centroid is a random point, each next one a point drawn
+// with a probability proportional to its squared distance to the closest centroid.
+fn initial_centroids(points: &[Point], k: usize, random: &mut Random) -> Vec<Point> {
+    if points.is_empty() {
+        return vec![];
+    }
+
+    let mut centroids = vec![points[random.below(points.len())]];
+    let mut distances: Vec<f32> = points
+        .iter()
+        .map(|point| squared_euclidean(point, &centroids[0]))
+        .collect();
+
+    while centroids.len() < k.min(points.len()) {
+        let total: f64 = distances.iter().map(|&d| d as f64).sum();
+        // Every point is on a centroid already: the rest are duplicates.
+        if total <= 0.0 {
+            break;
+        }
+
+        let mut target = random.next_f64() * total;
+        let mut chosen = points.len() - 1;
+        for (i, &distance) in distances.iter().enumerate() {
+            target -= distance as f64;
+            if target < 0.0 {
+                chosen = i;
+                break;
+            }
+        }
+
+        let centroid = points[chosen];
+        for (i, point) in points.iter().enumerate() {
+            distances[i] = distances[i].min(squared_euclidean(point, &centroid));
+        }
+        centroids.push(centroid);
+    }
+
+    centroids
+}
+
+// Closest centroid of every point, the first one on ties.
+fn assign(points: &[Point], centroids: &[Point]) -> Vec<usize> {
+    parallel::map(points, |point| {
+        let mut closest = (f32::INFINITY, 0);
+        for (i, centroid) in centroids.iter().enumerate() {
+            let distance = squared_euclidean(point, centroid);
+            if distance < closest.0 {
+                closest = (distance, i);
+            }
+        }
+        closest.1
+    })
+}
+
+// Mean of the points of every centroid. Centroids without points stay in place.
+fn update(points: &[Point], assignments: &[usize], centroids: &[Point]) -> Vec<Point> {
+    let mut sums = vec![[0.0f64; 768]; centroids.len()];
+    let mut counts = vec![0usize; centroids.len()];
+
+    for (point, &centroid) in points.iter().zip(assignments) {
+        counts[centroid] += 1;
+        for (sum, x) in sums[centroid].iter_mut().zip(point) {
+            *sum += *x as f64;
+        }
+    }
+
+    sums.into_iter()
+        .zip(counts)
+        .zip(centroids)
+        .map(|((sum, count), centroid)| match count {
+            0 => *centroid,
+            _ => sum.map(|x| (x / count as f64) as f32),
+        })
+        .collect()
+}
@@ -0,0 +1,47 @@
+use anyhow::bail;
+
+// Compressed indexes start with this header, followed by the codec. A serialized
+// index is JSON, which never starts with a NUL byte, so the two can't be confused.
+const MAGIC: &[u8; 4] = b"\0voy";
+
+// zlib stream: deflate with an adler32 checksum of the uncompressed bytes.
+#[cfg(feature = "compression")]
+const ZLIB: u8 = 1;
+
+pub fn is_compressed(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+#[cfg(feature = "compression")]
+pub fn compress(serialized: &str) -> Vec<u8> {
+    let mut compressed = MAGIC.to_vec();
+    compressed.push(ZLIB);
+    compressed.extend(miniz_oxide::deflate::compress_to_vec_zlib(
+        serialized.as_bytes(),
+        6,
+    ));
+
+    compressed
+}
+
+#[cfg(feature = "compression")]
+pub fn decompress(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    match bytes.get(MAGIC.len()) {
+        Some(&ZLIB) => {
+            match miniz_oxide::inflate::decompress_to_vec_zlib(&bytes[MAGIC.len() + 1..]) {
+                Ok(decompressed) => Ok(decompressed),
+                Err(error) => bail!(
+                    "failed to decompress the index, it may have been truncated: {:?}",
+                    error
+                ),
+            }
+        }
+        Some(codec) => bail!("unsupported compression codec {}", codec),
+        None => bail!("the compressed index is empty"),
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+pub fn decompress(_: &[u8]) -> anyhow::Result<Vec<u8>> {
+    bail!("the index is compressed but voy was built without the \"compression\" feature")
+}
@@ -0,0 +1,181 @@
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::engine::{entries, get, get_entry, Document, Index};
+use super::precision::Embeddings;
+use super::segment::{point, squared_euclidean, Chunks, Entry};
+
+// What "add" does with a document that is within the duplicate threshold of a
+// document already in the index, or of one added before it in the same call.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+    // Add it like any other document.
+    #[default]
+    Keep,
+    // Fail the add, leaving the index as it was.
+    Reject,
+    // Don't add it, and merge its metadata into the document it duplicates.
+    Merge,
+}
+
+impl DuplicatePolicy {
+    pub fn is_keep(&self) -> bool {
+        *self == DuplicatePolicy::Keep
+    }
+}
+
+// Groups of documents that are within "threshold", in euclidean distance, of
+// another document of the group. The distance between two documents is the one
+// between their closest chunks. Each group is ordered by id, and so are the groups.
+pub fn find_duplicates(index: &Index, threshold: f32) -> Vec<Vec<&Document>> {
+    let documents: Vec<(u64, &Document, &Embeddings)> = entries(index).collect();
+    let positions: HashMap<u64, usize> = documents
+        .iter()
+        .enumerate()
+        .map(|(i, (id, _, _))| (*id, i))
+        .collect();
+
+    let mut groups = Groups::new(documents.len());
+    for (i, (_, _, chunks)) in documents.iter().enumerate() {
+        for chunk in chunks.decode().iter() {
+            for (_, id) in within(index, chunk, threshold) {
+                if let Some(&j) = positions.get(&id) {
+                    groups.join(i, j);
+                }
+            }
+        }
+    }
+
+    let mut members: HashMap<usize, Vec<&Document>> = HashMap::new();
+    for (i, (_, document, _)) in documents.iter().enumerate() {
+        members.entry(groups.root(i)).or_default().push(document);
+    }
+    let mut duplicates: Vec<Vec<&Document>> = members
+        .into_values()
+        .filter(|members| members.len() > 1)
+        .collect();
+    for members in duplicates.iter_mut() {
+        members.sort_by(|a, b| a.id.cmp(&b.id));
+    }
+    duplicates.sort_by(|a, b| a[0].id.cmp(&b[0].id));
+
+    duplicates
+}
+
+// Applies the duplicate policy of the index to the entries about to be added.
+// Merged entries are replaced by the documents they were merged into.
+pub fn deduplicate(index: &Index, entries: Vec<Entry>) -> anyhow::Result<Vec<Entry>> {
+    let (policy, threshold) = (index.options.duplicates, index.options.duplicate_threshold);
+    if policy.is_keep() {
+        return Ok(entries);
+    }
+
+    let mut accepted: Vec<Entry> = vec![];
+    for entry in entries {
+        let (_, document, chunks) = &entry;
+        // Entries added in the same call shadow the documents of the index.
+        let pending = accepted.iter().position(|(_, other, other_chunks)| {
+            other.id != document.id && distance(chunks, other_chunks) <= threshold * threshold
+        });
+        let original = match pending {
+            Some(i) => Some(i),
+            None => {
+                let shadowed = |other: &Document| {
+                    other.id == document.id || accepted.iter().any(|(_, a, _)| a.id == other.id)
+                };
+                duplicate_in_index(index, chunks, threshold, shadowed).map(
+                    |(id, original, chunks)| {
+                        accepted.push((id, original.to_owned(), chunks));
+                        accepted.len() - 1
+                    },
+                )
+            }
+        };
+
+        match original {
+            None => accepted.push(entry),
+            Some(original) if policy == DuplicatePolicy::Reject => bail!(
+                "resource \"{}\" is a duplicate of \"{}\"",
+                document.id,
+                accepted[original].1.id
+            ),
+            Some(original) => merge_metadata(&mut accepted[original].1, document),
+        }
+    }
+
+    Ok(accepted)
+}
+
+// Metadata of the duplicate is added to the original, overriding its values.
+fn merge_metadata(original: &mut Document, duplicate: &Document) {
+    for (field, value) in &duplicate.metadata.0 {
+        original.metadata.0.insert(field.to_owned(), *value);
+    }
+}
+
+// Closest document of the index within "threshold" of the chunks, leaving out
+// the documents for which "skip" is true.
+fn duplicate_in_index<'a>(
+    index: &'a Index,
+    chunks: &[Vec<f32>],
+    threshold: f32,
+    skip: impl Fn(&Document) -> bool,
+) -> Option<(u64, &'a Document, Chunks)> {
+    let (_, id) = chunks
+        .iter()
+        .flat_map(|chunk| within(index, chunk, threshold))
+        .filter(|(_, id)| get(index, id).is_some_and(|document| !skip(document)))
+        .min_by(|a, b| a.0.total_cmp(&b.0))?;
+    let (document, embeddings) = get_entry(index, &id)?;
+
+    Some((id, document, embeddings.decode().into_owned()))
+}
+
+// Documents with a chunk within "threshold" of the embeddings, with the squared
+// distance to it. A document can be listed once per matching chunk.
+fn within(index: &Index, embeddings: &[f32], threshold: f32) -> Vec<(f32, u64)> {
+    let query = point(embeddings);
+    let radius = threshold * threshold;
+
+    let mut neighbors = index.wal.within(&query, radius);
+    for segment in &index.segments {
+        neighbors.extend(segment.within(&query, radius));
+    }
+
+    neighbors
+}
+
+// Squared distance between the closest chunks of two documents.
+fn distance(a: &[Vec<f32>], b: &[Vec<f32>]) -> f32 {
+    a.iter()
+        .flat_map(|x| b.iter().map(move |y| squared_euclidean(x, y)))
+        .fold(f32::INFINITY, f32::min)
+}
+
+// Union-find over the positions of the documents.
+struct Groups {
+    parents: Vec<usize>,
+}
+
+impl Groups {
+    fn new(len: usize) -> Groups {
+        Groups {
+            parents: (0..len).collect(),
+        }
+    }
+
+    fn root(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+        i
+    }
+
+    fn join(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.root(a), self.root(b));
+        self.parents[a.max(b)] = a.min(b);
+    }
+}
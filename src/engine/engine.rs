@@ -1,16 +1,28 @@
-use crate::Resource;
-use kiddo::float::{distance::squared_euclidean, kdtree::KdTree};
+use crate::{EmbeddedResource, Resource};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, convert::TryInto};
+use std::cmp::Ordering;
+use std::collections::HashSet;
 
+use super::boost::{boost, Boost, Metadata, BOOST_CANDIDATES};
+use super::changes::ChangeLog;
+use super::duplicates::deduplicate;
 use super::hash;
+use super::parallel;
+use super::precision::Embeddings;
+use super::projection::Projection;
+use super::reduction::{learn, reduce, reduce_entries};
+use super::segment::{point, sort_by_distance, Chunks, Entry, Point, Segment, WriteAheadLog};
+use super::tree::IndexOptions;
+use super::validation::{accept_all, expected_dimension, validate, Rejection};
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Hash)]
 
 pub struct Document {
     pub id: String,
     pub title: String,
     pub url: String,
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    pub metadata: Metadata,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -20,115 +32,519 @@ pub enum Query {
     Embeddings(Vec<f32>),
 }
 
-// Wasm has a 4GB memory limit. Should make sure the bucket size and capacity
-// doesn't exceed it and cause stack overflow.
-// More detail: https://v8.dev/blog/4gb-wasm-memory
-const BUCKET_SIZE: usize = 32;
+// How the chunk scores of a document add up to the score of the document.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Aggregation {
+    #[default]
+    Max,
+    Mean,
+    Sum,
+}
 
-pub type Tree = KdTree<f32, u64, 768, BUCKET_SIZE, u16>;
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    pub aggregation: Aggregation,
+    // Number of best scoring chunks per document that "Mean" and "Sum" take into account.
+    pub top_chunks: usize,
+    pub boosts: Vec<Boost>,
+    // Ids of documents that are never returned.
+    pub exclude_ids: HashSet<String>,
+    // When set, only documents with these ids are returned.
+    pub allow_ids: Option<HashSet<String>>,
+}
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl SearchOptions {
+    pub fn is_filtered(&self) -> bool {
+        !self.exclude_ids.is_empty() || self.allow_ids.is_some()
+    }
+
+    // Whether the document with this id may be returned.
+    pub fn allows(&self, id: &str) -> bool {
+        !self.exclude_ids.contains(id)
+            && self
+                .allow_ids
+                .as_ref()
+                .map_or(true, |allow_ids| allow_ids.contains(id))
+    }
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            aggregation: Aggregation::Max,
+            top_chunks: 3,
+            boosts: vec![],
+            exclude_ids: HashSet::new(),
+            allow_ids: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Neighbor {
+    pub document: Document,
+    // Position of the best matching chunk of the document.
+    pub chunk: usize,
+    // Similarity to the query after boosts, higher is better.
+    pub score: f32,
+}
+
+// Number of documents the write-ahead log holds before it's flushed into a segment.
+pub const WAL_CAPACITY: usize = 256;
+
+// Number of segments after which a flush triggers a full compaction.
+pub const MAX_SEGMENTS: usize = 8;
+
+// Resources at least this large are split into one segment per thread, and the
+// segments are built in parallel. Only applies with the "threads" feature.
+pub const PARALLEL_BUILD_THRESHOLD: usize = WAL_CAPACITY * 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Index {
-    // "IDX" is set to u16 to optimize CPU cache.
-    // Read more: https://github.com/sdd/kiddo/blob/7a0bb6ecce39963b27ffdca913c6be7a265e3523/src/types.rs#L35
-    pub tree: Tree,
-    pub data: HashMap<u64, Document>,
+    // Options of the segment trees, kept so that flushes and compactions build
+    // their trees the same way.
+    #[serde(default)]
+    pub options: IndexOptions,
+    pub segments: Vec<Segment>,
+    pub wal: WriteAheadLog,
+    // Versions of the index and of its documents. Flushes and compactions move
+    // documents around without changing them, so they leave the versions as
+    // they are. Compactions only drop the removed documents.
+    #[serde(default)]
+    pub changes: ChangeLog,
+    // Principal components learned for a PCA reduction.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub projection: Option<Box<Projection>>,
 }
 
-pub fn index(resource: Resource) -> anyhow::Result<Index> {
-    let data_vec: Vec<(u64, Document)> = resource
-        .embeddings
-        .iter()
-        .map(|resource| Document {
-            id: resource.id.to_owned(),
-            title: resource.title.to_owned(),
-            url: resource.url.to_owned(),
-        })
-        .map(|document| (hash(&document), document))
-        .collect();
+fn document(resource: &EmbeddedResource) -> Document {
+    Document {
+        id: resource.id.to_owned(),
+        title: resource.title.to_owned(),
+        url: resource.url.to_owned(),
+        metadata: Metadata(resource.metadata.to_owned().unwrap_or_default()),
+    }
+}
 
-    let data: HashMap<u64, Document> = data_vec.clone().into_iter().collect();
+// Takes the resource by value so its embeddings are moved rather than copied.
+pub fn entry(resource: EmbeddedResource) -> Entry {
+    let document = document(&resource);
 
-    let mut tree: Tree = KdTree::new();
+    let chunks: Chunks = match resource.chunks {
+        Some(chunks) if !chunks.is_empty() => chunks,
+        _ => vec![resource.embeddings],
+    };
 
-    resource
-        .embeddings
-        .iter()
-        .zip(data_vec.iter())
-        .for_each(|(resource, data)| {
-            let mut embeddings = resource.embeddings.clone();
-            embeddings.resize(768, 0.0);
+    (hash(&document), document, chunks)
+}
+
+pub fn index(resource: Resource, options: &IndexOptions) -> anyhow::Result<Index> {
+    let entries: Vec<Entry> = resource.embeddings.into_iter().map(entry).collect();
+
+    build_reduced(entries, options)
+}
+
+// Builds a new index from entries that aren't validated nor reduced yet,
+// learning the reduction from them if it needs to. Fails if any entry is invalid.
+pub fn build_reduced(entries: Vec<Entry>, options: &IndexOptions) -> anyhow::Result<Index> {
+    options.validate()?;
+
+    let (entries, rejected) = validate(options, None, entries);
+    accept_all(&rejected)?;
+
+    let projection = learn(options, &entries)?;
+    let entries = reduce_entries(options, projection.as_ref(), entries);
+    let mut index = build(entries, options)?;
+    index.projection = projection.map(Box::new);
+
+    Ok(index)
+}
 
-            let query: &[f32; 768] = &embeddings.try_into().unwrap();
-            // "item" holds the position of the document in "data"
-            tree.add(query, data.0);
-        });
+// Replaces the documents of the index with the resource, keeping its options.
+pub fn rebuild(index: &mut Index, resource: Resource) -> anyhow::Result<()> {
+    let rebuilt = self::index(resource, &index.options)?;
+    replace(index, rebuilt);
 
-    Ok(Index { tree, data })
+    Ok(())
 }
 
-pub fn search<'a>(index: &'a Index, query: &'a Query, k: usize) -> anyhow::Result<Vec<Document>> {
-    let mut query: Vec<f32> = match query {
-        Query::Embeddings(q) => q.to_owned(),
+// Replaces the index with another one. For its version, this is a clear
+// followed by an add of every document.
+pub fn replace(index: &mut Index, mut replacement: Index) {
+    let mut changes = std::mem::take(&mut index.changes);
+    changes.bump();
+    changes.cleared();
+    for (id, _, _) in entries(&replacement) {
+        changes.added(id, false);
+    }
+    replacement.changes = changes;
+    *index = replacement;
+}
+
+// Builds the segments of a new index from all of its entries, already reduced.
+pub fn build(mut entries: Vec<Entry>, options: &IndexOptions) -> anyhow::Result<Index> {
+    options.validate()?;
+
+    let mut index = Index {
+        options: *options,
+        ..Index::default()
+    };
+    if entries.is_empty() {
+        return Ok(index);
+    }
+
+    let partitions = parallel::threads().min(MAX_SEGMENTS);
+    if partitions < 2 || entries.len() < PARALLEL_BUILD_THRESHOLD {
+        index.segments.push(Segment::build(entries, options)?);
+        return Ok(index);
+    }
+
+    let size = entries.len().div_ceil(partitions);
+    let mut partitioned: Vec<Vec<Entry>> = vec![];
+    while !entries.is_empty() {
+        let rest = entries.split_off(size.min(entries.len()));
+        partitioned.push(std::mem::replace(&mut entries, rest));
+    }
+    index.segments = parallel::map_owned(partitioned, |entries| Segment::build(entries, options))
+        .into_iter()
+        .collect::<anyhow::Result<Vec<Segment>>>()?;
+
+    Ok(index)
+}
+
+pub fn search<'a>(
+    index: &'a Index,
+    query: &'a Query,
+    k: usize,
+    options: &'a SearchOptions,
+) -> anyhow::Result<Vec<Neighbor>> {
+    let query = match query {
+        Query::Embeddings(q) => point(&reduce(&index.options, index.projection.as_deref(), q)),
+    };
+
+    Ok(neighbors(index, rank(index, &query, k, options)))
+}
+
+// Runs several searches at once, in parallel with the "threads" feature.
+pub fn search_batch(
+    index: &Index,
+    queries: &[Query],
+    k: usize,
+    options: &SearchOptions,
+) -> anyhow::Result<Vec<Vec<Neighbor>>> {
+    parallel::map(queries, |query| search(index, query, k, options))
+        .into_iter()
+        .collect()
+}
+
+// Searches with the stored embeddings of a document, using its first chunk.
+// Returns no result if the id isn't in the index.
+pub fn search_similar_to(
+    index: &Index,
+    id: &str,
+    k: usize,
+    include_self: bool,
+) -> anyhow::Result<Vec<Neighbor>> {
+    let target = entries(index).find(|(_, document, _)| document.id == id);
+    let (target, query) = match target {
+        Some((target, _, chunks)) => (
+            target,
+            point(chunks.decode().first().map_or(&[], Vec::as_slice)),
+        ),
+        None => return Ok(vec![]),
     };
-    query.resize(768, 0.0);
 
-    let query: &[f32; 768] = &query.try_into().unwrap();
-    let neighbors = index.tree.nearest_n(query, k, &squared_euclidean);
+    let ranked = rank(index, &query, k + 1, &SearchOptions::default())
+        .into_iter()
+        .filter(|(_, id, _)| include_self || *id != target)
+        .take(k)
+        .collect();
+
+    Ok(neighbors(index, ranked))
+}
+
+// Best k documents for the query as (score, id, best chunk), highest score first.
+fn rank(index: &Index, query: &Point, k: usize, options: &SearchOptions) -> Vec<(f32, u64, usize)> {
+    // Documents are found through their closest chunk. The other aggregations
+    // and boosts rerank a wider pool of candidates.
+    let mut ranked: Vec<(f32, u64, usize)> = nearest(index, query, candidates(k, options), options)
+        .into_iter()
+        .filter_map(|(_, id)| {
+            let (document, chunks) = get_entry(index, &id)?;
+            let (score, chunk) = aggregate(query, chunks, options);
+            Some((boost(score, &document.metadata, &options.boosts), id, chunk))
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+    ranked.truncate(k);
+
+    ranked
+}
 
-    let mut result: Vec<Document> = vec![];
+fn neighbors(index: &Index, ranked: Vec<(f32, u64, usize)>) -> Vec<Neighbor> {
+    let mut result: Vec<Neighbor> = vec![];
 
-    for neighbor in &neighbors {
-        let doc = index.data.get(&neighbor.item);
+    for (score, id, chunk) in ranked {
+        let doc = get(index, &id);
         if let Some(document) = doc {
-            result.push(document.to_owned());
+            result.push(Neighbor {
+                document: document.to_owned(),
+                chunk,
+                score,
+            });
         }
     }
 
-    Ok(result)
+    result
+}
+
+// Number of documents to score for the best k.
+pub fn candidates(k: usize, options: &SearchOptions) -> usize {
+    let candidates = match options.aggregation {
+        Aggregation::Max => k,
+        _ => k * options.top_chunks.max(1),
+    };
+    match options.boosts.is_empty() {
+        true => candidates,
+        false => candidates * BOOST_CANDIDATES,
+    }
 }
 
-pub fn add<'a>(index: &'a mut Index, resource: &'a Resource) {
-    for item in &resource.embeddings {
-        let mut embeddings = item.embeddings.clone();
-        embeddings.resize(768, 0.0);
+// Turns a distance into a similarity in (0, 1], so that chunk scores can be summed.
+fn similarity(distance: f32) -> f32 {
+    1.0 / (1.0 + distance)
+}
 
-        let query: &[f32; 768] = &embeddings.try_into().unwrap();
-        let doc = Document {
-            id: item.id.to_owned(),
-            title: item.title.to_owned(),
-            url: item.url.to_owned(),
-        };
-        let id = hash(&doc);
-        index.data.insert(id, doc);
-        index.tree.add(query, id);
+// Score of a document for the query, and the position of its best matching chunk.
+pub fn aggregate(query: &Point, chunks: &Embeddings, options: &SearchOptions) -> (f32, usize) {
+    let (distance, best) = chunks.closest(query);
+    if options.aggregation == Aggregation::Max || chunks.len() == 1 {
+        return (similarity(distance), best);
     }
+
+    let mut scores: Vec<f32> = (0..chunks.len())
+        .map(|chunk| similarity(chunks.distance(chunk, query)))
+        .collect();
+    scores.sort_by(|a, b| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    scores.truncate(options.top_chunks.max(1));
+
+    let sum: f32 = scores.iter().sum();
+    match options.aggregation {
+        Aggregation::Mean => (sum / scores.len() as f32, best),
+        _ => (sum, best),
+    }
+}
+
+// Nearest k distinct documents allowed by the options, by the distance to their
+// closest chunk.
+pub fn nearest(index: &Index, query: &Point, k: usize, options: &SearchOptions) -> Vec<(f32, u64)> {
+    let mut neighbors = index.wal.search(query, k, options);
+    for result in parallel::map(&index.segments, |segment| segment.search(query, k, options)) {
+        neighbors.extend(result);
+    }
+    sort_by_distance(&mut neighbors);
+    neighbors.truncate(k);
+
+    neighbors
+}
+
+fn chunks<'a>(index: &'a Index, id: &u64) -> Option<&'a Embeddings> {
+    index.wal.embeddings.get(id).or_else(|| {
+        index
+            .segments
+            .iter()
+            .find_map(|segment| segment.embeddings.get(id))
+    })
+}
+
+pub fn get_entry<'a>(index: &'a Index, id: &u64) -> Option<(&'a Document, &'a Embeddings)> {
+    Some((get(index, id)?, chunks(index, id)?))
+}
+
+pub fn get<'a>(index: &'a Index, id: &u64) -> Option<&'a Document> {
+    index.wal.data.get(id).or_else(|| {
+        index
+            .segments
+            .iter()
+            .find_map(|segment| segment.data.get(id))
+    })
+}
+
+// Embeddings of documents that have none.
+static NO_EMBEDDINGS: Embeddings = Embeddings::F32(Vec::new());
+
+// Every document in the index together with its hash and embeddings.
+pub fn entries(index: &Index) -> impl Iterator<Item = (u64, &Document, &Embeddings)> {
+    let wal = index
+        .wal
+        .data
+        .iter()
+        .map(move |(id, document)| (id, document, index.wal.embeddings.get(id)));
+    let segments = index.segments.iter().flat_map(|segment| {
+        segment
+            .data
+            .iter()
+            .map(move |(id, document)| (id, document, segment.embeddings.get(id)))
+    });
+
+    wal.chain(segments)
+        .map(|(id, document, embeddings)| (*id, document, embeddings.unwrap_or(&NO_EMBEDDINGS)))
+}
+
+pub fn find<'a>(index: &'a Index, id: &str) -> Option<(&'a Document, &'a Embeddings)> {
+    entries(index)
+        .find(|(_, document, _)| document.id == id)
+        .map(|(_, document, embeddings)| (document, embeddings))
+}
+
+// Documents ordered by id so that pages stay stable between calls.
+pub fn documents(index: &Index, offset: usize, limit: usize) -> Vec<(&Document, &Embeddings)> {
+    let mut entries: Vec<(u64, &Document, &Embeddings)> = entries(index).collect();
+    entries.sort_by(|a, b| a.1.id.cmp(&b.1.id).then(a.0.cmp(&b.0)));
+
+    entries
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(|(_, document, embeddings)| (document, embeddings))
+        .collect()
+}
+
+// Adds the valid resources, and returns why the others were rejected.
+pub fn add<'a>(index: &'a mut Index, resource: &'a Resource) -> anyhow::Result<Vec<Rejection>> {
+    let entries: Vec<Entry> = resource.embeddings.iter().cloned().map(entry).collect();
+    let (entries, rejected) = validate(&index.options, expected_dimension(index), entries);
+    // An index built empty learns its reduction from the first resources added,
+    // which must be enough to learn it from.
+    if index.projection.is_none() {
+        index.projection = learn(&index.options, &entries)?.map(Box::new);
+    }
+    let entries = reduce_entries(&index.options, index.projection.as_deref(), entries);
+    let entries = deduplicate(index, entries)?;
+    add_entries(index, entries)?;
+
+    Ok(rejected)
+}
+
+pub fn add_entries(index: &mut Index, entries: Vec<Entry>) -> anyhow::Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    index.changes.bump();
+
+    for (id, doc, embeddings) in entries {
+        let embeddings = index.options.precision.store(embeddings);
+        // The newest version of a document lives in the log and shadows older ones.
+        let mut existed = index.wal.data.contains_key(&id);
+        for segment in index.segments.iter_mut() {
+            existed |= segment.delete(&id);
+        }
+        index.wal.insert(id, doc, embeddings);
+        index.changes.added(id, existed);
+
+        if index.wal.len() >= WAL_CAPACITY {
+            flush(index)?;
+        }
+    }
+
+    Ok(())
 }
 
 pub fn remove<'a>(index: &'a mut Index, resource: &'a Resource) {
-    for item in &resource.embeddings {
-        let mut embeddings = item.embeddings.clone();
-        embeddings.resize(768, 0.0);
+    let documents: Vec<Document> = resource.embeddings.iter().map(document).collect();
+
+    remove_documents(index, &documents);
+}
+
+pub fn remove_documents(index: &mut Index, documents: &[Document]) {
+    let mut bumped = false;
+
+    for document in documents {
+        let id = hash(document);
+
+        let mut removed = index.wal.remove(&id);
+        for segment in index.segments.iter_mut() {
+            removed |= segment.delete(&id);
+        }
+
+        // Only removals of documents that were in the index bump the version.
+        if removed {
+            if !bumped {
+                index.changes.bump();
+                bumped = true;
+            }
+            index.changes.removed(id, document.to_owned());
+        }
+    }
+}
+
+// Number of tree points the entries add up to.
+fn points<'a>(entries: impl Iterator<Item = &'a Embeddings>) -> usize {
+    entries.map(Embeddings::len).sum()
+}
+
+pub fn flush(index: &mut Index) -> anyhow::Result<()> {
+    if index.wal.is_empty() {
+        return Ok(());
+    }
+
+    // Checked before draining so that nothing is lost when the tree doesn't fit.
+    index
+        .options
+        .check_capacity(points(index.wal.embeddings.values()))?;
+    let entries = index.wal.drain();
+    index
+        .segments
+        .push(Segment::build(entries, &index.options)?);
+
+    if index.segments.len() > MAX_SEGMENTS {
+        compact_segments(index)?;
+    }
+
+    Ok(())
+}
 
-        let query: &[f32; 768] = &embeddings.try_into().unwrap();
-        let id = hash(&Document {
-            id: item.id.to_owned(),
-            title: item.title.to_owned(),
-            url: item.url.to_owned(),
-        });
+// Merges the segments and drops the removed documents kept for syncing, so
+// changes since an earlier version are exported as a whole new index.
+pub fn compact(index: &mut Index) -> anyhow::Result<()> {
+    compact_segments(index)?;
+    index.changes.truncate();
 
-        index.tree.remove(query, id);
-        index.data.remove(&id);
+    Ok(())
+}
+
+fn compact_segments(index: &mut Index) -> anyhow::Result<()> {
+    let live = index
+        .segments
+        .iter()
+        .flat_map(|segment| segment.embeddings.values())
+        .chain(index.wal.embeddings.values());
+    index.options.check_capacity(points(live))?;
+
+    let mut entries: Vec<Entry> = index.wal.drain();
+    for segment in index.segments.drain(..) {
+        entries.extend(segment.into_entries());
     }
+
+    if !entries.is_empty() {
+        index
+            .segments
+            .push(Segment::build(entries, &index.options)?);
+    }
+
+    Ok(())
 }
 
-pub fn clear<'a>(index: &'a mut Index) {
-    // simply assign a new tree and data because traversing the nodes to perform removal is the only alternative.
-    // Kiddo provides only basic removal. See more: https://github.com/sdd/kiddo/issues/76
-    index.tree = KdTree::new();
-    index.data = HashMap::new();
+pub fn clear(index: &mut Index) {
+    // Segments are immutable, so they are dropped rather than emptied.
+    index.segments = vec![];
+    index.wal = WriteAheadLog::default();
+
+    index.changes.bump();
+    index.changes.cleared();
 }
 
-pub fn size<'a>(index: &'a Index) -> usize {
-    index.data.len()
+pub fn size(index: &Index) -> usize {
+    let segments: usize = index.segments.iter().map(Segment::len).sum();
+    segments + index.wal.len()
 }
@@ -0,0 +1,247 @@
+use anyhow::{anyhow, bail, Context};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+
+use super::changes::MAX_VERSION;
+use super::compression::{decompress, is_compressed};
+use super::engine::{build, Document, Index};
+use super::frozen::is_frozen;
+use super::integrity::checksum;
+use super::namespace::Namespaces;
+use super::segment::Entry;
+use super::tree::IndexOptions;
+
+// Version of the serialized form written by this build. Bump it whenever the
+// serialized structs change, add a golden fixture of the new version to the
+// tests and migrate the previous version in `deserialize`.
+// Fields that older readers can skip don't need a new version.
+//
+//   1  segments, a write-ahead log, the versions and metadata of the documents
+//
+// Indexes written before versions were introduced hold a single tree, see
+// `TreeIndex`.
+pub const FORMAT_VERSION: u32 = 1;
+
+// What a serialized string holds: a single index or every namespace of a Voy instance.
+#[derive(Debug)]
+pub enum Snapshot {
+    Index(Box<Index>),
+    Namespaces(Namespaces),
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    version: u32,
+    checksum: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<&'a Index>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespaces: Option<&'a Namespaces>,
+}
+
+#[derive(Deserialize)]
+struct Versioned {
+    checksum: u32,
+    index: Option<Index>,
+    namespaces: Option<Namespaces>,
+}
+
+// Tells the formats apart by their top level keys, skipping over the values.
+#[derive(Deserialize)]
+struct Probe {
+    version: Option<u32>,
+    tree: Option<IgnoredAny>,
+}
+
+pub fn serialize_index(index: &Index) -> anyhow::Result<String> {
+    let mut serialized = vec![];
+    write_index(&mut serialized, index)?;
+
+    Ok(String::from_utf8(serialized)?)
+}
+
+// Writes the same JSON as `serialize_index`, without buffering it.
+pub fn write_index(writer: impl io::Write, index: &Index) -> anyhow::Result<()> {
+    Ok(serde_json::to_writer(
+        writer,
+        &Envelope {
+            version: FORMAT_VERSION,
+            checksum: checksum([index]),
+            index: Some(index),
+            namespaces: None,
+        },
+    )?)
+}
+
+pub fn serialize_namespaces(namespaces: &Namespaces) -> anyhow::Result<String> {
+    Ok(serde_json::to_string(&Envelope {
+        version: FORMAT_VERSION,
+        checksum: checksum(namespaces.indexes.values()),
+        index: None,
+        namespaces: Some(namespaces),
+    })?)
+}
+
+pub fn deserialize(serialized: &str) -> anyhow::Result<Snapshot> {
+    let probe: Probe = serde_json::from_str(serialized)
+        .context("malformed serialized index, it may have been truncated")?;
+
+    match probe {
+        Probe {
+            version: Some(version),
+            ..
+        } => {
+            if version > FORMAT_VERSION {
+                bail!(
+                    "incompatible index format version {}: this version of voy reads up to \
+                     version {}, upgrade voy to load this index",
+                    version,
+                    FORMAT_VERSION
+                );
+            }
+            let versioned: Versioned = serde_json::from_str(serialized)
+                .with_context(|| format!("malformed index of format version {}", version))?;
+            let mut snapshot = match versioned {
+                Versioned {
+                    index: Some(index), ..
+                } => Snapshot::Index(Box::new(index)),
+                Versioned {
+                    namespaces: Some(namespaces),
+                    ..
+                } => Snapshot::Namespaces(namespaces),
+                _ => bail!("serialized index holds neither an index nor namespaces"),
+            };
+
+            let actual = match &snapshot {
+                Snapshot::Index(index) => checksum([index.as_ref()]),
+                Snapshot::Namespaces(namespaces) => checksum(namespaces.indexes.values()),
+            };
+            if actual != versioned.checksum {
+                bail!(
+                    "checksum mismatch: the serialized index was written with checksum {:08x} \
+                     but its documents and embeddings hash to {:08x}, it is corrupted",
+                    versioned.checksum,
+                    actual
+                );
+            }
+
+            let indexes: Vec<&mut Index> = match &mut snapshot {
+                Snapshot::Index(index) => vec![index.as_mut()],
+                Snapshot::Namespaces(namespaces) => namespaces.indexes.values_mut().collect(),
+            };
+            for index in indexes {
+                if index.changes.version > MAX_VERSION {
+                    bail!(
+                        "the serialized index is corrupted: its version {} exceeds the largest \
+                         supported, {}",
+                        index.changes.version,
+                        MAX_VERSION
+                    );
+                }
+                // Logs of version 1 indexes hold their embeddings in f32 whatever
+                // the precision of the index.
+                index.wal.store(index.options.precision);
+            }
+
+            Ok(snapshot)
+        }
+        Probe { tree: Some(_), .. } => {
+            let legacy: TreeIndex =
+                serde_json::from_str(serialized).context("malformed unversioned index")?;
+            Ok(Snapshot::Index(Box::new(legacy.migrate()?)))
+        }
+        _ => Err(anyhow!("unrecognized serialized index format")),
+    }
+}
+
+// Reads an index serialized as JSON or compressed, told apart by their header.
+pub fn deserialize_bytes(bytes: &[u8]) -> anyhow::Result<Snapshot> {
+    if is_frozen(bytes) {
+        bail!("the index is frozen, it can only be searched as a FrozenIndex");
+    }
+
+    let decompressed;
+    let bytes = if is_compressed(bytes) {
+        decompressed = decompress(bytes)?;
+        &decompressed
+    } else {
+        bytes
+    };
+
+    let serialized = std::str::from_utf8(bytes)
+        .context("malformed serialized index, it is neither JSON nor compressed")?;
+    deserialize(serialized)
+}
+
+pub fn deserialize_index(serialized: &str) -> anyhow::Result<Index> {
+    deserialize(serialized)?.into_index()
+}
+
+impl Snapshot {
+    pub fn into_index(self) -> anyhow::Result<Index> {
+        match self {
+            Snapshot::Index(index) => Ok(*index),
+            Snapshot::Namespaces(_) => {
+                bail!(
+                    "expected a single index but found namespaces, load them with Voy.deserialize()"
+                )
+            }
+        }
+    }
+}
+
+// The unversioned formats, written before the format version was introduced.
+
+// The original format: a single tree next to the documents. The embeddings only
+// live in the tree, so they are read back from its leaves.
+#[derive(Deserialize)]
+struct TreeIndex {
+    tree: TreePoints,
+    data: HashMap<u64, Document>,
+}
+
+// The leaves of a tree of kiddo 2, the k-d tree crate the original format was
+// written with. Each leaf holds "size" points, padded to 768 dimensions, and the
+// items they point to. The stems are skipped.
+#[derive(Deserialize)]
+struct TreePoints {
+    leaves: Vec<LeafPoints>,
+}
+
+#[derive(Deserialize)]
+struct LeafPoints {
+    content_points: Vec<f32>,
+    content_items: Vec<u64>,
+    size: u64,
+}
+
+impl TreePoints {
+    // Items and their points, in leaf order.
+    fn points(&self) -> impl Iterator<Item = (u64, &[f32])> + '_ {
+        self.leaves.iter().flat_map(|leaf| {
+            leaf.content_items
+                .iter()
+                .copied()
+                .zip(leaf.content_points.chunks(768))
+                .take(leaf.size as usize)
+        })
+    }
+}
+
+impl TreeIndex {
+    fn migrate(self) -> anyhow::Result<Index> {
+        let TreeIndex { tree, mut data } = self;
+
+        let mut entries: Vec<Entry> = vec![];
+        for (item, point) in tree.points() {
+            // Embeddings were padded to 768 dimensions with zeros.
+            let length = point.iter().rposition(|x| *x != 0.0).map_or(0, |i| i + 1);
+            if let Some(document) = data.remove(&item) {
+                entries.push((item, document, vec![point[..length].to_vec()]));
+            }
+        }
+
+        build(entries, &IndexOptions::default())
+    }
+}
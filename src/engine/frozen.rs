@@ -0,0 +1,606 @@
+use anyhow::{bail, Context};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::convert::TryInto;
+
+use super::boost::{boost, Metadata};
+use super::bulk::{self, LEAF};
+use super::engine::{
+    aggregate, candidates, entries, Document, Index, Neighbor, Query, SearchOptions,
+};
+use super::precision::Embeddings;
+use super::segment::{point, Candidates};
+
+// A read-only index that is searched in place, straight from its bytes. Opening
+// it checks the header and the offsets the sections hold, but none of the
+// coordinates or strings, so it's ready for queries without a copy of the index.
+//
+// All numbers are little endian u32, except for coordinates and split values
+// which are f32. The header is followed by these sections, in order:
+//
+//   stems             per stem: split dimension, split value, left, right
+//   leaves            per leaf: first point, number of points
+//   points            per point: "dimensions" coordinates
+//   point documents   per point: document
+//   document chunks   per document: first entry in "chunks", number of chunks
+//   chunks            per chunk: point, in the order the chunks were indexed
+//   document strings  per document: offset and length of its id, title, url and
+//                     metadata, as JSON
+//   strings           UTF-8 bytes
+//
+// Version 1 has no metadata in the document strings.
+//
+// Leaves are ranges of the points, which are stored in the order of the tree.
+// Nodes are referenced by their position, with LEAF set for leaves. Stems come
+// before their children, so a stem only points to stems after it, and every
+// node but the root has a single parent.
+const MAGIC: &[u8; 4] = b"\0vyf";
+
+pub const FROZEN_VERSION: u32 = 2;
+
+const HEADER_BYTES: usize = 36;
+
+// Most dimensions the queries are padded to.
+const MAX_DIMENSIONS: usize = 768;
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    dimensions: usize,
+    points: usize,
+    stems: usize,
+    leaves: usize,
+    documents: usize,
+    root: u32,
+    strings: usize,
+    // Strings per document, which depends on the version.
+    fields: usize,
+}
+
+// Offsets of the sections in the bytes.
+#[derive(Debug, Clone, Copy)]
+struct Sections {
+    stems: usize,
+    leaves: usize,
+    points: usize,
+    point_documents: usize,
+    document_chunks: usize,
+    chunks: usize,
+    document_strings: usize,
+    strings: usize,
+    end: usize,
+}
+
+impl Header {
+    // None if the offsets overflow, which only a corrupted header leads to.
+    fn sections(&self) -> Option<Sections> {
+        let after = |start: usize, count: usize, bytes: usize| {
+            count
+                .checked_mul(bytes)
+                .and_then(|size| start.checked_add(size))
+        };
+        let stems = HEADER_BYTES;
+        let leaves = after(stems, self.stems, 16)?;
+        let points = after(leaves, self.leaves, 8)?;
+        let point_documents = after(points, self.points, self.dimensions.checked_mul(4)?)?;
+        let document_chunks = after(point_documents, self.points, 4)?;
+        let chunks = after(document_chunks, self.documents, 8)?;
+        let document_strings = after(chunks, self.points, 4)?;
+        let strings = after(
+            document_strings,
+            self.documents,
+            self.fields.checked_mul(8)?,
+        )?;
+
+        Some(Sections {
+            stems,
+            leaves,
+            points,
+            point_documents,
+            document_chunks,
+            chunks,
+            document_strings,
+            strings,
+            end: after(strings, self.strings, 1)?,
+        })
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_f32(bytes: &[u8], offset: usize) -> f32 {
+    f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn extend_u32(bytes: &mut Vec<u8>, value: usize) {
+    bytes.extend((value as u32).to_le_bytes());
+}
+
+// Writes the live documents of the index in the read-only format.
+pub fn freeze(index: &Index) -> anyhow::Result<Vec<u8>> {
+    // Queries of a frozen index aren't reduced or normalized, so they wouldn't
+    // match.
+    if index.options.reduction.is_some() {
+        bail!("indexes that reduce their embeddings can't be frozen");
+    }
+    if index.options.normalize {
+        bail!("indexes that normalize their embeddings can't be frozen");
+    }
+
+    let mut documents: Vec<(u64, &Document, &Embeddings)> = entries(index).collect();
+    // Sorted so that freezing the same index always gives the same bytes.
+    documents.sort_by_key(|(id, _, _)| *id);
+
+    // Embeddings and position of the chunk of every point.
+    let mut points: Vec<(&Embeddings, usize)> = vec![];
+    let mut point_documents: Vec<usize> = vec![];
+    for (i, (_, _, embeddings)) in documents.iter().enumerate() {
+        for chunk in 0..embeddings.len() {
+            points.push((embeddings, chunk));
+            point_documents.push(i);
+        }
+    }
+    if points.len() >= LEAF as usize {
+        bail!(
+            "{} points exceed the capacity of a frozen index",
+            points.len()
+        );
+    }
+
+    let dimensions = points
+        .iter()
+        .map(|(embeddings, chunk)| embeddings.dimension(*chunk))
+        .max()
+        .unwrap_or(0);
+    if dimensions > MAX_DIMENSIONS {
+        bail!(
+            "embeddings of {} dimensions exceed the {} supported",
+            dimensions,
+            MAX_DIMENSIONS
+        );
+    }
+
+    let coordinate = |point: usize, dimension: usize| {
+        let (embeddings, chunk) = points[point];
+        embeddings.coordinate(chunk, dimension)
+    };
+    let tree = bulk::build(
+        points.len(),
+        dimensions,
+        index.options.bucket_size,
+        coordinate,
+    );
+
+    // Position of every point once reordered, to list the chunks of the documents.
+    let mut positions = vec![0; points.len()];
+    for (position, point) in tree.order.iter().enumerate() {
+        positions[*point] = position;
+    }
+
+    let metadata = documents
+        .iter()
+        .map(|(_, document, _)| serde_json::to_string(&document.metadata))
+        .collect::<Result<Vec<String>, _>>()?;
+    let fields = |i: usize| {
+        let document = documents[i].1;
+        [&document.id, &document.title, &document.url, &metadata[i]]
+    };
+    let strings: usize = (0..documents.len())
+        .map(|i| fields(i).iter().map(|field| field.len()).sum::<usize>())
+        .sum();
+
+    let mut bytes = MAGIC.to_vec();
+    for value in [
+        FROZEN_VERSION as usize,
+        dimensions,
+        points.len(),
+        tree.stems.len(),
+        tree.leaves.len(),
+        documents.len(),
+        tree.root as usize,
+        strings,
+    ] {
+        extend_u32(&mut bytes, value);
+    }
+
+    for stem in &tree.stems {
+        extend_u32(&mut bytes, stem.dimension as usize);
+        bytes.extend(stem.split.to_le_bytes());
+        extend_u32(&mut bytes, stem.left as usize);
+        extend_u32(&mut bytes, stem.right as usize);
+    }
+    for (start, len) in &tree.leaves {
+        extend_u32(&mut bytes, *start as usize);
+        extend_u32(&mut bytes, *len as usize);
+    }
+    for point in &tree.order {
+        for dimension in 0..dimensions {
+            bytes.extend(coordinate(*point, dimension).to_le_bytes());
+        }
+    }
+    for point in &tree.order {
+        extend_u32(&mut bytes, point_documents[*point]);
+    }
+
+    let mut first = 0;
+    for (_, _, embeddings) in &documents {
+        extend_u32(&mut bytes, first);
+        extend_u32(&mut bytes, embeddings.len());
+        first += embeddings.len();
+    }
+    for position in &positions {
+        extend_u32(&mut bytes, *position);
+    }
+
+    let mut offset = 0;
+    for i in 0..documents.len() {
+        for field in fields(i) {
+            extend_u32(&mut bytes, offset);
+            extend_u32(&mut bytes, field.len());
+            offset += field.len();
+        }
+    }
+    for i in 0..documents.len() {
+        for field in fields(i) {
+            bytes.extend(field.as_bytes());
+        }
+    }
+
+    Ok(bytes)
+}
+
+pub fn is_frozen(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+#[derive(Debug, Clone)]
+pub struct FrozenIndex<B> {
+    bytes: B,
+    header: Header,
+    sections: Sections,
+}
+
+impl<B: AsRef<[u8]>> FrozenIndex<B> {
+    // Checks the header, the length of the bytes and every offset they hold, so
+    // that searches never read out of bounds.
+    pub fn open(bytes: B) -> anyhow::Result<FrozenIndex<B>> {
+        let data = bytes.as_ref();
+        if !is_frozen(data) {
+            bail!("not a frozen index, create one with freeze()");
+        }
+        if data.len() < HEADER_BYTES {
+            bail!("the frozen index is truncated: its header is incomplete");
+        }
+
+        let version = read_u32(data, 4);
+        if version > FROZEN_VERSION {
+            bail!(
+                "incompatible frozen index version {}: this version of voy reads up to version \
+                 {}, upgrade voy to load this index",
+                version,
+                FROZEN_VERSION
+            );
+        }
+
+        let field = |i: usize| read_u32(data, 8 + i * 4) as usize;
+        let header = Header {
+            dimensions: field(0),
+            points: field(1),
+            stems: field(2),
+            leaves: field(3),
+            documents: field(4),
+            root: field(5) as u32,
+            strings: field(6),
+            fields: if version < 2 { 3 } else { 4 },
+        };
+        if header.dimensions > MAX_DIMENSIONS {
+            bail!(
+                "the frozen index is corrupted: its {} dimensions exceed the {} supported",
+                header.dimensions,
+                MAX_DIMENSIONS
+            );
+        }
+        let sections = header
+            .sections()
+            .context("the frozen index is corrupted: its header describes too many bytes")?;
+        if data.len() != sections.end {
+            bail!(
+                "the frozen index is truncated or corrupted: it has {} bytes but its header \
+                 describes {}",
+                data.len(),
+                sections.end
+            );
+        }
+
+        let index = FrozenIndex {
+            bytes,
+            header,
+            sections,
+        };
+        index.check()?;
+
+        Ok(index)
+    }
+
+    // Checks that the nodes, points, chunks and strings only refer to what the
+    // sections hold.
+    fn check(&self) -> anyhow::Result<()> {
+        let bytes = self.bytes.as_ref();
+        let header = &self.header;
+        let sections = &self.sections;
+        let corrupted = |what: &str| anyhow::anyhow!("the frozen index is corrupted: {}", what);
+        // Whether the range runs past the limit, with u32 offsets that could
+        // overflow a 32 bit usize.
+        let past = |start: u32, len: u32, limit: usize| {
+            (start as usize)
+                .checked_add(len as usize)
+                .map_or(true, |end| end > limit)
+        };
+
+        // A stem only points to stems after it, which rules out cycles, and
+        // every node has a single parent, which keeps a search from reaching a
+        // node through several paths.
+        let node = |node: u32, parent: Option<usize>| match node & LEAF != 0 {
+            true => ((node & !LEAF) as usize) < header.leaves,
+            false => (node as usize) < header.stems && parent.map_or(true, |p| node as usize > p),
+        };
+        let mut parented = vec![false; header.stems + header.leaves];
+        let mut parent = |node: u32| {
+            let slot = match node & LEAF != 0 {
+                true => header.stems + (node & !LEAF) as usize,
+                false => node as usize,
+            };
+            !std::mem::replace(&mut parented[slot], true)
+        };
+        if header.points > 0 && !(node(header.root, None) && parent(header.root)) {
+            return Err(corrupted("its root is out of bounds"));
+        }
+        for stem in 0..header.stems {
+            let offset = sections.stems + stem * 16;
+            let dimension = read_u32(bytes, offset) as usize;
+            let (left, right) = (read_u32(bytes, offset + 8), read_u32(bytes, offset + 12));
+            if dimension >= header.dimensions.max(1)
+                || !node(left, Some(stem))
+                || !node(right, Some(stem))
+            {
+                return Err(corrupted("a stem is out of bounds"));
+            }
+            if !parent(left) || !parent(right) {
+                return Err(corrupted("a node has several parents"));
+            }
+        }
+        for leaf in 0..header.leaves {
+            let offset = sections.leaves + leaf * 8;
+            let (start, len) = (read_u32(bytes, offset), read_u32(bytes, offset + 4));
+            if past(start, len, header.points) {
+                return Err(corrupted("a leaf is out of bounds"));
+            }
+        }
+        for point in 0..header.points {
+            if self.point_document(point) >= header.documents
+                || read_u32(bytes, sections.chunks + point * 4) as usize >= header.points
+            {
+                return Err(corrupted("a point is out of bounds"));
+            }
+        }
+        for document in 0..header.documents {
+            let offset = sections.document_chunks + document * 8;
+            let (first, count) = (read_u32(bytes, offset), read_u32(bytes, offset + 4));
+            if past(first, count, header.points) {
+                return Err(corrupted("the chunks of a document are out of bounds"));
+            }
+        }
+        for field in 0..header.documents * header.fields {
+            let offset = sections.document_strings + field * 8;
+            let (start, len) = (read_u32(bytes, offset), read_u32(bytes, offset + 4));
+            if past(start, len, header.strings) {
+                return Err(corrupted("a string is out of bounds"));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn size(&self) -> usize {
+        self.header.documents
+    }
+
+    pub fn search(
+        &self,
+        query: &Query,
+        k: usize,
+        options: &SearchOptions,
+    ) -> anyhow::Result<Vec<Neighbor>> {
+        let query = match query {
+            Query::Embeddings(q) => point(q),
+        };
+
+        // Same ranking as a search of the index: documents are found through their
+        // closest chunk, then scored over their chunks and boosted.
+        let mut ranked: Vec<(f32, usize, usize)> = vec![];
+        for (_, document) in self.nearest(&query, candidates(k, options), options)? {
+            let chunks = Embeddings::F32(self.chunks(document));
+            let (mut score, chunk) = aggregate(&query, &chunks, options);
+            if !options.boosts.is_empty() {
+                score = boost(score, &self.metadata(document)?, &options.boosts);
+            }
+            ranked.push((score, document, chunk));
+        }
+        ranked.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+        ranked.truncate(k);
+
+        ranked
+            .into_iter()
+            .map(|(score, document, chunk)| {
+                Ok(Neighbor {
+                    document: self.document(document)?,
+                    chunk,
+                    score,
+                })
+            })
+            .collect()
+    }
+
+    // Nearest k distinct documents by the distance to their closest chunk.
+    // Documents the options don't allow are skipped as the tree is visited.
+    fn nearest(
+        &self,
+        query: &[f32],
+        k: usize,
+        options: &SearchOptions,
+    ) -> anyhow::Result<Vec<(f32, usize)>> {
+        if k == 0 || self.header.points == 0 {
+            return Ok(vec![]);
+        }
+
+        // Squared coordinates of the query past the stored dimensions, which are
+        // zero in every point.
+        let rest: f32 = query[self.header.dimensions..].iter().map(|x| x * x).sum();
+
+        // Keep doubling while chunks of the same documents crowd out the others.
+        let mut n = k;
+        loop {
+            let mut candidates = Candidates::new(n);
+            self.visit(query, rest, options, &mut candidates);
+            let exhausted = candidates.points.len() < n;
+
+            let mut seen = HashSet::new();
+            let result: Vec<(f32, usize)> = candidates
+                .points
+                .into_iter()
+                .map(|(distance, point)| (distance, self.point_document(point)))
+                .filter(|(_, document)| seen.insert(*document))
+                .take(k)
+                .collect();
+
+            if result.len() == k || exhausted {
+                return Ok(result);
+            }
+            n *= 2;
+        }
+    }
+
+    // Walks the tree nearest side first, like `Tree::walk`, with a stack rather
+    // than recursion so that the depth of the tree doesn't matter.
+    fn visit(
+        &self,
+        query: &[f32],
+        rest: f32,
+        options: &SearchOptions,
+        candidates: &mut Candidates<usize>,
+    ) {
+        let bytes = self.bytes.as_ref();
+        // Nodes left to visit, with a lower bound of their distance to the query.
+        let mut stack = vec![(self.header.root, 0.0)];
+
+        while let Some((node, distance)) = stack.pop() {
+            if distance > candidates.worst() {
+                continue;
+            }
+
+            if node & LEAF != 0 {
+                let leaf = self.sections.leaves + (node & !LEAF) as usize * 8;
+                let start = read_u32(bytes, leaf) as usize;
+                let len = read_u32(bytes, leaf + 4) as usize;
+                for point in start..start + len {
+                    if options.is_filtered() && !self.allows(point, options) {
+                        continue;
+                    }
+                    candidates.push(self.distance(point, query) + rest, point);
+                }
+                continue;
+            }
+
+            let stem = self.sections.stems + node as usize * 16;
+            let dimension = read_u32(bytes, stem) as usize;
+            let split = read_f32(bytes, stem + 4);
+            let (left, right) = (read_u32(bytes, stem + 8), read_u32(bytes, stem + 12));
+
+            let difference = query[dimension] - split;
+            let (near, far) = if difference < 0.0 {
+                (left, right)
+            } else {
+                (right, left)
+            };
+            stack.push((far, f32::max(distance, difference * difference)));
+            stack.push((near, distance));
+        }
+    }
+
+    // Whether the options allow the document of the point. Points of documents
+    // with a corrupted id are left out.
+    fn allows(&self, point: usize, options: &SearchOptions) -> bool {
+        self.field(self.point_document(point), 0)
+            .is_ok_and(|id| options.allows(id))
+    }
+
+    // Squared euclidean distance over the stored dimensions.
+    fn distance(&self, point: usize, query: &[f32]) -> f32 {
+        let bytes = self.bytes.as_ref();
+        let dimensions = self.header.dimensions;
+        let start = self.sections.points + point * dimensions * 4;
+
+        (0..dimensions)
+            .map(|i| {
+                let difference = query[i] - read_f32(bytes, start + i * 4);
+                difference * difference
+            })
+            .sum()
+    }
+
+    fn point_document(&self, point: usize) -> usize {
+        read_u32(
+            self.bytes.as_ref(),
+            self.sections.point_documents + point * 4,
+        ) as usize
+    }
+
+    // Embeddings of every chunk of the document, in the order they were indexed.
+    fn chunks(&self, document: usize) -> Vec<Vec<f32>> {
+        let bytes = self.bytes.as_ref();
+        let dimensions = self.header.dimensions;
+        let entry = self.sections.document_chunks + document * 8;
+        let (first, count) = (read_u32(bytes, entry) as usize, read_u32(bytes, entry + 4));
+
+        (first..first + count as usize)
+            .map(|chunk| {
+                let point = read_u32(bytes, self.sections.chunks + chunk * 4) as usize;
+                let start = self.sections.points + point * dimensions * 4;
+                (0..dimensions)
+                    .map(|i| read_f32(bytes, start + i * 4))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn field(&self, document: usize, i: usize) -> anyhow::Result<&str> {
+        let bytes = self.bytes.as_ref();
+        let entry = self.sections.document_strings + (document * self.header.fields + i) * 8;
+        let offset = self.sections.strings + read_u32(bytes, entry) as usize;
+        let len = read_u32(bytes, entry + 4) as usize;
+        let field = bytes
+            .get(offset..offset + len)
+            .context("the frozen index is corrupted: a string is out of bounds")?;
+
+        std::str::from_utf8(field).context("the frozen index is corrupted: a string isn't UTF-8")
+    }
+
+    fn metadata(&self, document: usize) -> anyhow::Result<Metadata> {
+        if self.header.fields < 4 {
+            return Ok(Metadata::default());
+        }
+
+        serde_json::from_str(self.field(document, 3)?)
+            .context("the frozen index is corrupted: the metadata of a document isn't JSON")
+    }
+
+    fn document(&self, document: usize) -> anyhow::Result<Document> {
+        Ok(Document {
+            id: self.field(document, 0)?.to_owned(),
+            title: self.field(document, 1)?.to_owned(),
+            url: self.field(document, 2)?.to_owned(),
+            metadata: self.metadata(document)?,
+        })
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+
+use super::engine::{entries, get, nearest, Document, Index, SearchOptions};
+use super::parallel;
+use super::precision::Embeddings;
+use super::segment::point;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node<'a> {
+    pub document: &'a Document,
+    // Nearest documents with their euclidean distance, closest first.
+    pub neighbors: Vec<(&'a Document, f32)>,
+}
+
+// The k nearest documents of every document, leaving out the edges of at least
+// "threshold" when it's given. The distance between two documents is the one
+// between their closest chunks. Nodes are ordered by id.
+pub fn knn_graph(index: &Index, k: usize, threshold: Option<f32>) -> Vec<Node<'_>> {
+    let mut documents: Vec<(u64, &Document, &Embeddings)> = entries(index).collect();
+    documents.sort_by(|a, b| a.1.id.cmp(&b.1.id).then(a.0.cmp(&b.0)));
+
+    let options = SearchOptions::default();
+    parallel::map(&documents, |(id, document, chunks)| {
+        // The nearest documents of each chunk, one more to make up for the document
+        // itself, include the k nearest of the whole document.
+        let mut distances: HashMap<u64, f32> = HashMap::new();
+        for chunk in chunks.decode().iter() {
+            for (distance, other) in nearest(index, &point(chunk), k + 1, &options) {
+                let closest = distances.entry(other).or_insert(distance);
+                *closest = closest.min(distance);
+            }
+        }
+        distances.remove(id);
+
+        let mut neighbors: Vec<(&Document, f32)> = distances
+            .into_iter()
+            .filter_map(|(other, distance)| Some((get(index, &other)?, distance.sqrt())))
+            .filter(|(_, distance)| threshold.map_or(true, |threshold| *distance < threshold))
+            .collect();
+        neighbors.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.id.cmp(&b.0.id)));
+        neighbors.truncate(k);
+
+        Node {
+            document,
+            neighbors,
+        }
+    })
+}
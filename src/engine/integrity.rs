@@ -0,0 +1,265 @@
+use anyhow::bail;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::bulk::LEAF;
+use super::engine::{Document, Index};
+use super::precision::Embeddings;
+use super::segment::{Segment, WriteAheadLog};
+use super::tree::Tree;
+
+// CRC32 of the documents and embeddings of the indexes, in a fixed order so it
+// doesn't depend on the order of the hash maps. The trees are left out: they are
+// checked against the embeddings by `verify`.
+pub fn checksum<'a>(indexes: impl IntoIterator<Item = &'a Index>) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+
+    for index in indexes {
+        for segment in &index.segments {
+            update(&mut hasher, &segment.data, &segment.embeddings);
+
+            let mut tombstones: Vec<&u64> = segment.tombstones.iter().collect();
+            tombstones.sort();
+            for id in tombstones {
+                hasher.update(&id.to_le_bytes());
+            }
+        }
+        update(&mut hasher, &index.wal.data, &index.wal.embeddings);
+    }
+
+    hasher.finalize()
+}
+
+fn update(
+    hasher: &mut crc32fast::Hasher,
+    data: &HashMap<u64, Document>,
+    embeddings: &HashMap<u64, Embeddings>,
+) {
+    let data: BTreeMap<&u64, &Document> = data.iter().collect();
+
+    for (id, document) in data {
+        hasher.update(&id.to_le_bytes());
+        for field in [&document.id, &document.title, &document.url] {
+            hasher.update(&(field.len() as u64).to_le_bytes());
+            hasher.update(field.as_bytes());
+        }
+        for (field, value) in &document.metadata.0 {
+            hasher.update(&(field.len() as u64).to_le_bytes());
+            hasher.update(field.as_bytes());
+            hasher.update(&value.to_le_bytes());
+        }
+
+        // Values are hashed in f32 whatever the precision they're held in.
+        let chunks = embeddings
+            .get(id)
+            .map(Embeddings::decode)
+            .unwrap_or_default();
+        hasher.update(&(chunks.len() as u64).to_le_bytes());
+        for chunk in chunks.iter() {
+            hasher.update(&(chunk.len() as u64).to_le_bytes());
+            for value in chunk {
+                hasher.update(&value.to_le_bytes());
+            }
+        }
+    }
+}
+
+// Checks that the parts of the index agree with each other: every document has
+// embeddings, the trees are well formed with every point on the side of the
+// splits above it, and they hold exactly the embeddings of the documents that
+// weren't removed.
+pub fn verify(index: &Index) -> anyhow::Result<()> {
+    for (i, segment) in index.segments.iter().enumerate() {
+        verify_segment(segment).map_err(|error| error.context(format!("segment {}", i)))?;
+    }
+    verify_wal(&index.wal).map_err(|error| error.context("write-ahead log"))
+}
+
+fn verify_embeddings(
+    data: &HashMap<u64, Document>,
+    embeddings: &HashMap<u64, Embeddings>,
+) -> anyhow::Result<()> {
+    for (id, document) in data {
+        if !embeddings.contains_key(id) {
+            bail!("document \"{}\" has no embeddings", document.id);
+        }
+    }
+    if let Some(id) = embeddings.keys().find(|id| !data.contains_key(id)) {
+        bail!("item {} has embeddings but no document", id);
+    }
+    Ok(())
+}
+
+// A split above a leaf, and the side of it the leaf is on.
+#[derive(Clone, Copy)]
+struct Split {
+    dimension: u32,
+    value: f32,
+    left: bool,
+}
+
+// Points of a leaf with the splits above it.
+type Leaf<'a> = (&'a [(u64, u32)], Vec<Split>);
+
+// Walks the tree from its root and returns the points of every leaf with the
+// splits above it. Fails unless every node reference is in bounds, every node
+// is reached exactly once and the leaves hold every point exactly once.
+fn leaves(tree: &Tree) -> anyhow::Result<Vec<Leaf<'_>>> {
+    if tree.stems.is_empty() && tree.leaves.is_empty() && tree.points.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut stems = vec![false; tree.stems.len()];
+    let mut reached = vec![false; tree.leaves.len()];
+    let mut covered = 0;
+    let mut leaves = vec![];
+    let mut stack = vec![(tree.root, vec![])];
+
+    while let Some((node, splits)) = stack.pop() {
+        if node & LEAF != 0 {
+            let leaf = (node & !LEAF) as usize;
+            if leaf >= tree.leaves.len() {
+                bail!("the tree points to leaf {} of {}", leaf, tree.leaves.len());
+            }
+            if std::mem::replace(&mut reached[leaf], true) {
+                bail!("leaf {} of the tree is reached twice", leaf);
+            }
+            let Some(points) = tree.leaf(node) else {
+                let (start, len) = tree.leaves[leaf];
+                bail!(
+                    "leaf {} holds points {} to {} of {}",
+                    leaf,
+                    start,
+                    start as u64 + len as u64,
+                    tree.points.len()
+                );
+            };
+            covered += points.len();
+            leaves.push((points, splits));
+            continue;
+        }
+
+        let Some(stem) = tree.stems.get(node as usize) else {
+            bail!("the tree points to stem {} of {}", node, tree.stems.len());
+        };
+        if std::mem::replace(&mut stems[node as usize], true) {
+            bail!("stem {} of the tree is reached twice", node);
+        }
+        for (child, left) in [(stem.left, true), (stem.right, false)] {
+            let mut splits = splits.clone();
+            splits.push(Split {
+                dimension: stem.dimension,
+                value: stem.split,
+                left,
+            });
+            stack.push((child, splits));
+        }
+    }
+
+    if let Some(stem) = stems.iter().position(|reached| !reached) {
+        bail!("stem {} of the tree can't be reached from its root", stem);
+    }
+    if let Some(leaf) = reached.iter().position(|reached| !reached) {
+        bail!("leaf {} of the tree can't be reached from its root", leaf);
+    }
+    // Leaves reached once and holding as many points as the tree can only miss
+    // a point by overlapping another leaf.
+    if covered != tree.points.len() {
+        bail!(
+            "the leaves of the tree hold {} points but it has {}",
+            covered,
+            tree.points.len()
+        );
+    }
+    let mut starts: Vec<(u32, u32)> = tree.leaves.clone();
+    starts.sort_unstable();
+    if starts
+        .windows(2)
+        .any(|pair| pair[0].0 + pair[0].1 > pair[1].0)
+    {
+        bail!("leaves of the tree overlap");
+    }
+
+    Ok(leaves)
+}
+
+fn verify_wal(wal: &WriteAheadLog) -> anyhow::Result<()> {
+    verify_embeddings(&wal.data, &wal.embeddings)
+}
+
+fn verify_segment(segment: &Segment) -> anyhow::Result<()> {
+    verify_embeddings(&segment.data, &segment.embeddings)?;
+
+    if let Some(id) = segment
+        .tombstones
+        .iter()
+        .find(|id| segment.data.contains_key(id))
+    {
+        bail!(
+            "document \"{}\" is both live and removed",
+            segment.data[id].id
+        );
+    }
+
+    // Points of the tree per live document, each chunk at most once.
+    let mut found: HashMap<u64, HashSet<u32>> = HashMap::new();
+    for (points, splits) in leaves(&segment.tree)? {
+        for (item, chunk) in points {
+            if segment.tombstones.contains(item) {
+                continue;
+            }
+            let Some(document) = segment.data.get(item) else {
+                bail!(
+                    "the tree holds item {} that is neither a document nor removed",
+                    item
+                );
+            };
+            let embeddings = &segment.embeddings[item];
+            if *chunk as usize >= embeddings.len() {
+                bail!(
+                    "the tree holds chunk {} of document \"{}\" but it has {} embeddings",
+                    chunk,
+                    document.id,
+                    embeddings.len()
+                );
+            }
+            if !found.entry(*item).or_default().insert(*chunk) {
+                bail!(
+                    "the tree holds chunk {} of document \"{}\" twice",
+                    chunk,
+                    document.id
+                );
+            }
+
+            for split in &splits {
+                let coordinate = embeddings.coordinate(*chunk as usize, split.dimension as usize);
+                if (split.left && coordinate > split.value)
+                    || (!split.left && coordinate < split.value)
+                {
+                    bail!(
+                        "chunk {} of document \"{}\" is on the wrong side of the split at {} \
+                         on dimension {}",
+                        chunk,
+                        document.id,
+                        split.value,
+                        split.dimension
+                    );
+                }
+            }
+        }
+    }
+
+    for (id, document) in &segment.data {
+        let chunks = segment.embeddings[id].len();
+        let points = found.get(id).map_or(0, HashSet::len);
+        if points != chunks {
+            bail!(
+                "document \"{}\" has {} embeddings but {} points in the tree",
+                document.id,
+                chunks,
+                points
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::engine::{build, entries, Index};
+use super::segment::Entry;
+use super::stats::dimension;
+
+// What to do when both indexes hold a document with the same id.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeConflict {
+    // Fail the merge.
+    #[default]
+    Fail,
+    // Keep the document of the first index.
+    Keep,
+    // Replace it with the document of the second index.
+    Replace,
+}
+
+// Combines the documents of two indexes into a new one, with the options of the
+// first, reusing the stored embeddings. The result is the same as indexing the
+// union of their resources.
+pub fn merge(a: &Index, b: &Index, conflict: MergeConflict) -> anyhow::Result<Index> {
+    // Every index uses the squared euclidean distance, so only the dimensions of
+    // the embeddings can be incompatible.
+    let (dimension_a, dimension_b) = (dimension(a), dimension(b));
+    if dimension_a != 0 && dimension_b != 0 && dimension_a != dimension_b {
+        bail!(
+            "can't merge indexes of different dimensions: {} and {}",
+            dimension_a,
+            dimension_b
+        );
+    }
+
+    // Embeddings are stored reduced, so both indexes must reduce them the same way.
+    let projections = (a.projection.as_ref(), b.projection.as_ref());
+    if a.options.reduction != b.options.reduction
+        || matches!(projections, (Some(x), Some(y)) if x != y)
+    {
+        bail!("can't merge indexes that reduce their embeddings differently");
+    }
+    // The stored embeddings are normalized and rounded when they are indexed, so
+    // they would differ from those the first index stores.
+    if a.options.normalize != b.options.normalize {
+        bail!("can't merge an index that normalizes its embeddings with one that doesn't");
+    }
+    if a.options.precision != b.options.precision {
+        bail!(
+            "can't merge indexes of different precisions: {:?} and {:?}",
+            a.options.precision,
+            b.options.precision
+        );
+    }
+
+    let mut merged: Vec<Entry> = vec![];
+    let mut positions: HashMap<&str, usize> = HashMap::new();
+    for (id, document, chunks) in entries(a) {
+        positions.insert(&document.id, merged.len());
+        merged.push((id, document.to_owned(), chunks.decode().into_owned()));
+    }
+
+    for (id, document, chunks) in entries(b) {
+        let entry = (id, document.to_owned(), chunks.decode().into_owned());
+        match (positions.get(document.id.as_str()), conflict) {
+            (None, _) => merged.push(entry),
+            (Some(_), MergeConflict::Fail) => {
+                bail!(
+                    "both indexes hold a resource with the id \"{}\"",
+                    document.id
+                )
+            }
+            (Some(_), MergeConflict::Keep) => {}
+            (Some(position), MergeConflict::Replace) => merged[*position] = entry,
+        }
+    }
+
+    let mut index = build(merged, &a.options)?;
+    index.projection = projections.0.or(projections.1).cloned();
+
+    Ok(index)
+}
@@ -1,8 +1,62 @@
+mod boost;
+mod builder;
+mod bulk;
+mod changes;
+mod cluster;
+mod compression;
+mod duplicates;
+#[allow(clippy::module_inception)]
 mod engine;
+mod format;
+mod frozen;
+mod graph;
 mod hash;
+mod integrity;
+mod merge;
+mod namespace;
+mod parallel;
+mod precision;
+mod projection;
+mod random;
+mod reduction;
+mod segment;
+mod stats;
+mod tree;
+mod validation;
 
 #[cfg(test)]
 mod tests;
 
-pub use engine::{add, clear, index, remove, search, size, Index, Query};
+pub use boost::Boost;
+pub use builder::IndexBuilder;
+pub use changes::{apply_changes, export_changes_since, Delta, MAX_VERSION};
+pub use cluster::{cluster, Cluster};
+#[cfg(feature = "compression")]
+pub use compression::compress;
+pub use duplicates::{find_duplicates, DuplicatePolicy};
+pub use engine::{
+    add, clear, compact, documents, entry, find, index, rebuild, remove, replace, search,
+    search_batch, search_similar_to, size, Aggregation, Document, Index, Neighbor, Query,
+    SearchOptions,
+};
+pub use format::{
+    deserialize, deserialize_bytes, deserialize_index, serialize_index, serialize_namespaces,
+    Snapshot,
+};
+pub use frozen::{freeze, FrozenIndex};
+pub use graph::{knn_graph, Node};
 pub use hash::hash;
+pub use integrity::verify;
+pub use merge::{merge, MergeConflict};
+pub use namespace::{Namespaces, DEFAULT_NAMESPACE};
+pub use precision::Precision;
+pub use projection::{project, Projection, ProjectionMethod};
+pub use reduction::{reduce, Reduction, ReductionMethod};
+pub use stats::{stats, SegmentStats, Stats};
+pub use tree::{IndexOptions, IndexWidth};
+pub use validation::{accept_all, Reason, Rejection};
+
+#[cfg(test)]
+pub use engine::{flush, PARALLEL_BUILD_THRESHOLD, WAL_CAPACITY};
+#[cfg(test)]
+pub use format::FORMAT_VERSION;
@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use super::engine::{search, Index, Neighbor, Query, SearchOptions};
+use super::parallel;
+
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+// Named collections that live side by side in one Voy instance. Each one is a
+// complete index of its own, so it can be serialized and loaded independently.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Namespaces {
+    pub indexes: BTreeMap<String, Index>,
+}
+
+impl Namespaces {
+    pub fn new(index: Index) -> Namespaces {
+        let mut namespaces = Namespaces::default();
+        namespaces.insert(DEFAULT_NAMESPACE, index);
+
+        namespaces
+    }
+
+    pub fn get(&self, namespace: &str) -> Option<&Index> {
+        self.indexes.get(namespace)
+    }
+
+    // Returns the index of the namespace, creating an empty one if needed.
+    pub fn get_mut(&mut self, namespace: &str) -> &mut Index {
+        self.indexes.entry(namespace.to_owned()).or_default()
+    }
+
+    // Returns the index of the namespace, if it exists.
+    pub fn find_mut(&mut self, namespace: &str) -> Option<&mut Index> {
+        self.indexes.get_mut(namespace)
+    }
+
+    pub fn insert(&mut self, namespace: &str, index: Index) {
+        self.indexes.insert(namespace.to_owned(), index);
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.indexes.keys().cloned().collect()
+    }
+
+    // Searches each of the given namespaces and merges the results by score.
+    pub fn search(
+        &self,
+        namespaces: &[String],
+        query: &Query,
+        k: usize,
+        options: &SearchOptions,
+    ) -> anyhow::Result<Vec<(String, Neighbor)>> {
+        let mut result: Vec<(String, Neighbor)> = vec![];
+
+        let namespaces: BTreeSet<&String> = namespaces.iter().collect();
+        for namespace in namespaces {
+            if let Some(index) = self.get(namespace) {
+                let neighbors = search(index, query, k, options)?;
+                result.extend(
+                    neighbors
+                        .into_iter()
+                        .map(|neighbor| (namespace.to_owned(), neighbor)),
+                );
+            }
+        }
+
+        result.sort_by(|a, b| b.1.score.partial_cmp(&a.1.score).unwrap_or(Ordering::Equal));
+        result.truncate(k);
+
+        Ok(result)
+    }
+
+    pub fn search_batch(
+        &self,
+        namespaces: &[String],
+        queries: &[Query],
+        k: usize,
+        options: &SearchOptions,
+    ) -> anyhow::Result<Vec<Vec<(String, Neighbor)>>> {
+        parallel::map(queries, |query| self.search(namespaces, query, k, options))
+            .into_iter()
+            .collect()
+    }
+}
@@ -0,0 +1,50 @@
+// Data parallel helpers. With the "threads" feature they run on rayon's thread
+// pool, otherwise they fall back to plain iterators on the current thread.
+#[cfg(feature = "threads")]
+use rayon::prelude::*;
+
+#[cfg(feature = "threads")]
+pub fn map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync + Send,
+{
+    items.par_iter().map(f).collect()
+}
+
+#[cfg(not(feature = "threads"))]
+pub fn map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    F: Fn(&T) -> R,
+{
+    items.iter().map(f).collect()
+}
+
+#[cfg(feature = "threads")]
+pub fn map_owned<T, R, F>(items: Vec<T>, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync + Send,
+{
+    items.into_par_iter().map(f).collect()
+}
+
+#[cfg(not(feature = "threads"))]
+pub fn map_owned<T, R, F>(items: Vec<T>, f: F) -> Vec<R>
+where
+    F: Fn(T) -> R,
+{
+    items.into_iter().map(f).collect()
+}
+
+#[cfg(feature = "threads")]
+pub fn threads() -> usize {
+    rayon::current_num_threads()
+}
+
+#[cfg(not(feature = "threads"))]
+pub fn threads() -> usize {
+    1
+}
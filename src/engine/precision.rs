@@ -0,0 +1,253 @@
+use anyhow::{bail, Context};
+use half::{bf16, f16};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt::Write;
+use std::mem::size_of;
+
+use super::segment::{squared_euclidean, Chunks};
+
+// Precision the embeddings are stored with. Embeddings rounded to f16 or bf16
+// are held in 16 bits, and decoded to f32 as distances are computed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    #[default]
+    F32,
+    // IEEE half precision: 11 bits of significand, values up to 65504.
+    F16,
+    // The upper half of an f32: 8 bits of significand, the range of an f32.
+    Bf16,
+}
+
+impl Precision {
+    pub fn is_f32(&self) -> bool {
+        *self == Precision::F32
+    }
+
+    // The closest value the precision holds. Values out of the range of f16
+    // saturate to its largest finite value.
+    pub fn round(&self, value: f32) -> f32 {
+        match self {
+            Precision::F32 => value,
+            _ => self.decode(self.encode(value)),
+        }
+    }
+
+    // Bits of a value in f16 or bf16.
+    fn encode(&self, value: f32) -> u16 {
+        match self {
+            Precision::F16 => {
+                f16::from_f32(value.clamp(f16::MIN.into(), f16::MAX.into())).to_bits()
+            }
+            _ => bf16::from_f32(value).to_bits(),
+        }
+    }
+
+    fn decode(&self, bits: u16) -> f32 {
+        match self {
+            Precision::F16 => f16::from_bits(bits).into(),
+            _ => bf16::from_bits(bits).into(),
+        }
+    }
+
+    // Rounds the chunks to the precision and holds them in as many bits.
+    pub fn store(&self, chunks: Chunks) -> Embeddings {
+        let encode = |chunks: Chunks| -> Vec<Vec<u16>> {
+            chunks
+                .iter()
+                .map(|chunk| chunk.iter().map(|value| self.encode(*value)).collect())
+                .collect()
+        };
+
+        match self {
+            Precision::F32 => Embeddings::F32(chunks),
+            Precision::F16 => Embeddings::F16(encode(chunks)),
+            Precision::Bf16 => Embeddings::Bf16(encode(chunks)),
+        }
+    }
+
+    // Hex digits of a packed value.
+    pub fn digits(&self) -> usize {
+        match self {
+            Precision::F32 => 8,
+            _ => 4,
+        }
+    }
+
+    // Reads chunks packed by `Embeddings::pack`.
+    pub fn unpack(&self, packed: &[String]) -> anyhow::Result<Embeddings> {
+        let digits = self.digits();
+        let bits = packed.iter().map(|chunk| {
+            if !chunk.is_ascii() || chunk.len() % digits != 0 {
+                bail!("malformed packed embeddings of length {}", chunk.len());
+            }
+            (0..chunk.len())
+                .step_by(digits)
+                .map(|i| {
+                    u32::from_str_radix(&chunk[i..i + digits], 16)
+                        .context("malformed packed embeddings")
+                })
+                .collect::<anyhow::Result<Vec<u32>>>()
+        });
+        let halves = |bits: Vec<u32>| bits.into_iter().map(|bits| bits as u16).collect();
+
+        Ok(match self {
+            Precision::F32 => Embeddings::F32(
+                bits.map(|chunk| Ok(chunk?.into_iter().map(f32::from_bits).collect()))
+                    .collect::<anyhow::Result<Chunks>>()?,
+            ),
+            Precision::F16 => Embeddings::F16(
+                bits.map(|chunk| Ok(halves(chunk?)))
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            Precision::Bf16 => Embeddings::Bf16(
+                bits.map(|chunk| Ok(halves(chunk?)))
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+        })
+    }
+}
+
+// Embeddings of a document as they are stored, one per chunk, in the precision
+// of the index.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Embeddings {
+    F32(Chunks),
+    F16(Vec<Vec<u16>>),
+    Bf16(Vec<Vec<u16>>),
+}
+
+// Squared euclidean distance from the query to decoded values, with the same
+// zero padding as `squared_euclidean`.
+fn decoded_distance(query: &[f32], values: impl Iterator<Item = f32>) -> f32 {
+    let mut query = query.iter();
+    let mut sum = 0.0;
+    for x in values {
+        let y = query.next().copied().unwrap_or(0.0);
+        sum += (x - y) * (x - y);
+    }
+
+    sum + query.map(|y| y * y).sum::<f32>()
+}
+
+impl Embeddings {
+    pub fn precision(&self) -> Precision {
+        match self {
+            Embeddings::F32(_) => Precision::F32,
+            Embeddings::F16(_) => Precision::F16,
+            Embeddings::Bf16(_) => Precision::Bf16,
+        }
+    }
+
+    // Number of chunks.
+    pub fn len(&self) -> usize {
+        match self {
+            Embeddings::F32(chunks) => chunks.len(),
+            Embeddings::F16(chunks) | Embeddings::Bf16(chunks) => chunks.len(),
+        }
+    }
+
+    // Length of the chunk at "i".
+    pub fn dimension(&self, i: usize) -> usize {
+        match self {
+            Embeddings::F32(chunks) => chunks[i].len(),
+            Embeddings::F16(chunks) | Embeddings::Bf16(chunks) => chunks[i].len(),
+        }
+    }
+
+    // A coordinate of the chunk at "i", zero past its end.
+    pub fn coordinate(&self, i: usize, dimension: usize) -> f32 {
+        match self {
+            Embeddings::F32(chunks) => chunks[i].get(dimension).copied().unwrap_or(0.0),
+            Embeddings::F16(chunks) | Embeddings::Bf16(chunks) => chunks[i]
+                .get(dimension)
+                .map_or(0.0, |bits| self.precision().decode(*bits)),
+        }
+    }
+
+    // Squared euclidean distance from the query to the chunk at "i".
+    pub fn distance(&self, i: usize, query: &[f32]) -> f32 {
+        match self {
+            Embeddings::F32(chunks) => squared_euclidean(query, &chunks[i]),
+            Embeddings::F16(chunks) => {
+                decoded_distance(query, chunks[i].iter().map(|x| f16::from_bits(*x).into()))
+            }
+            Embeddings::Bf16(chunks) => {
+                decoded_distance(query, chunks[i].iter().map(|x| bf16::from_bits(*x).into()))
+            }
+        }
+    }
+
+    // Distance from the query to the closest chunk and the position of that chunk.
+    pub fn closest(&self, query: &[f32]) -> (f32, usize) {
+        (0..self.len())
+            .map(|i| (self.distance(i, query), i))
+            .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal))
+            .unwrap_or((f32::INFINITY, 0))
+    }
+
+    // The chunks in f32, only copied when they're stored in 16 bits.
+    pub fn decode(&self) -> Cow<'_, [Vec<f32>]> {
+        let decode = |chunks: &[Vec<u16>]| -> Chunks {
+            let precision = self.precision();
+            chunks
+                .iter()
+                .map(|chunk| chunk.iter().map(|bits| precision.decode(*bits)).collect())
+                .collect()
+        };
+
+        match self {
+            Embeddings::F32(chunks) => Cow::Borrowed(chunks),
+            Embeddings::F16(chunks) | Embeddings::Bf16(chunks) => Cow::Owned(decode(chunks)),
+        }
+    }
+
+    pub fn into_chunks(self) -> Chunks {
+        match self {
+            Embeddings::F32(chunks) => chunks,
+            embeddings => embeddings.decode().into_owned(),
+        }
+    }
+
+    // Packs the chunks as the hex of their bits, 4 characters per value in 16
+    // bits and 8 in f32, where JSON takes about 11 for an f32.
+    pub fn pack(&self) -> Vec<String> {
+        let digits = self.precision().digits();
+        match self {
+            Embeddings::F32(chunks) => chunks
+                .iter()
+                .map(|chunk| {
+                    let mut packed = String::with_capacity(chunk.len() * digits);
+                    for value in chunk {
+                        let _ = write!(packed, "{:08x}", value.to_bits());
+                    }
+                    packed
+                })
+                .collect(),
+            Embeddings::F16(chunks) | Embeddings::Bf16(chunks) => chunks
+                .iter()
+                .map(|chunk| {
+                    let mut packed = String::with_capacity(chunk.len() * digits);
+                    for bits in chunk {
+                        let _ = write!(packed, "{:04x}", bits);
+                    }
+                    packed
+                })
+                .collect(),
+        }
+    }
+
+    // Bytes taken by the values of the chunks.
+    pub fn memory_size(&self) -> usize {
+        match self {
+            Embeddings::F32(chunks) => {
+                chunks.iter().map(Vec::len).sum::<usize>() * size_of::<f32>()
+            }
+            Embeddings::F16(chunks) | Embeddings::Bf16(chunks) => {
+                chunks.iter().map(Vec::len).sum::<usize>() * size_of::<u16>()
+            }
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use super::engine::{documents, Document, Index};
+use super::random::Random;
+use super::segment::mean_chunk;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProjectionMethod {
+    // Principal component analysis: the directions of largest variance.
+    Pca,
+    // A random Gaussian matrix, which roughly preserves distances and needs no
+    // pass over the data.
+    Random { seed: u64 },
+}
+
+// A linear map to fewer dimensions: vectors are centered on "mean", then
+// multiplied by each of the components.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Projection {
+    pub mean: Vec<f32>,
+    pub components: Vec<Vec<f32>>,
+}
+
+// Projected documents, with their coordinates.
+pub type Coordinates<'a> = Vec<(&'a Document, Vec<f32>)>;
+
+// Most rounds of subspace iteration used to find the principal components.
+// Iteration stops earlier, usually after a few rounds, once the variance along
+// every component changes by less than PCA_TOLERANCE of the largest.
+const PCA_ITERATIONS: usize = 64;
+const PCA_TOLERANCE: f64 = 1e-9;
+
+impl Projection {
+    // The "dims" principal components of the points. Components are orthonormal
+    // and ordered by decreasing variance, each signed so that its largest
+    // coordinate is positive.
+    pub fn pca(points: &[Vec<f32>], dims: usize) -> Projection {
+        let dimension = points.iter().map(Vec::len).max().unwrap_or(0);
+        let mut mean = vec![0.0f64; dimension];
+        for point in points {
+            for (sum, x) in mean.iter_mut().zip(point) {
+                *sum += *x as f64 / points.len() as f64;
+            }
+        }
+        let centered: Vec<Vec<f64>> = points
+            .iter()
+            .map(|point| {
+                (0..dimension)
+                    .map(|i| point.get(i).copied().unwrap_or(0.0) as f64 - mean[i])
+                    .collect()
+            })
+            .collect();
+
+        // The components are the top eigenvectors of the covariance, a matrix of
+        // "dimension" rows. With fewer points than dimensions, the eigenvectors
+        // of the smaller Gram matrix of the points are found instead: "u" with
+        // variance "v" maps to the component of the covariance along the points
+        // weighted by "u", with the same variance.
+        let dims = dims.min(dimension);
+        let mut variances = if centered.len() < dimension {
+            let gram = symmetric(centered.len(), |i, j| {
+                dot(&centered[i], &centered[j]) / centered.len() as f64
+            });
+            let mut components: Vec<(f64, Vec<f64>)> = vec![];
+            let eigenvectors = eigenvectors(&gram, dims.min(centered.len()));
+            let largest = eigenvectors
+                .iter()
+                .map(|(variance, _)| *variance)
+                .fold(0.0, f64::max);
+            for (variance, weights) in eigenvectors {
+                let mut component = vec![0.0; dimension];
+                for (weight, point) in weights.iter().zip(&centered) {
+                    for (sum, x) in component.iter_mut().zip(point) {
+                        *sum += weight * x;
+                    }
+                }
+                // Centered points span one dimension less than their number, and
+                // the Gram matrix has no variance left along the last weights
+                // past rounding errors.
+                let norm = dot(&component, &component).sqrt();
+                if variance > PCA_TOLERANCE * largest && norm > 0.0 {
+                    component.iter_mut().for_each(|x| *x /= norm);
+                    components.push((variance, component));
+                }
+            }
+
+            // Any orthonormal directions complete the components past the span
+            // of the points, where there's no variance.
+            let mut basis: Vec<Vec<f64>> = components.iter().map(|(_, c)| c.to_owned()).collect();
+            complete(&mut basis, dimension, dims, &mut Random::new(0));
+            let completion = basis.split_off(components.len());
+            components.extend(completion.into_iter().map(|component| (0.0, component)));
+            components
+        } else {
+            let mut covariance = vec![vec![0.0; dimension]; dimension];
+            for point in &centered {
+                for i in 0..dimension {
+                    let weight = point[i] / centered.len() as f64;
+                    for j in i..dimension {
+                        covariance[i][j] += weight * point[j];
+                    }
+                }
+            }
+            let covariance = symmetric(dimension, |i, j| covariance[i.min(j)][i.max(j)]);
+            eigenvectors(&covariance, dims)
+        };
+
+        // Orthonormalizing in order keeps the components sorted by variance, but
+        // make sure of it in case of near-equal eigenvalues.
+        variances.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        Projection {
+            mean: mean.into_iter().map(|x| x as f32).collect(),
+            components: variances
+                .into_iter()
+                .map(|(_, component)| signed(component))
+                .collect(),
+        }
+    }
+
+    // A Gaussian matrix scaled by 1/sqrt(dims), so that distances are preserved
+    // on average.
+    pub fn random(dimension: usize, dims: usize, seed: u64) -> Projection {
+        let mut random = Random::new(seed);
+        let scale = 1.0 / (dims as f64).sqrt();
+
+        Projection {
+            mean: vec![0.0; dimension],
+            components: (0..dims)
+                .map(|_| {
+                    (0..dimension)
+                        .map(|_| (gaussian(&mut random) * scale) as f32)
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    pub fn dims(&self) -> usize {
+        self.components.len()
+    }
+
+    // Coordinates of the vector in the projected space. Vectors of another
+    // dimension are padded with zeros or truncated.
+    pub fn apply(&self, vector: &[f32]) -> Vec<f32> {
+        let centered: Vec<f32> = self
+            .mean
+            .iter()
+            .enumerate()
+            .map(|(i, mean)| vector.get(i).copied().unwrap_or(0.0) - mean)
+            .collect();
+
+        self.components
+            .iter()
+            .map(|component| component.iter().zip(&centered).map(|(a, b)| a * b).sum())
+            .collect()
+    }
+}
+
+// Projects every document of the index, represented by the mean of its chunks,
+// to "dims" dimensions. Documents are ordered by id. The chunks are in the
+// reduced space of the index, so queries need to be reduced before they are
+// projected.
+pub fn project(
+    index: &Index,
+    dims: usize,
+    method: ProjectionMethod,
+) -> anyhow::Result<(Projection, Coordinates<'_>)> {
+    if dims == 0 {
+        bail!("the projection needs at least 1 dimension");
+    }
+
+    let documents = documents(index, 0, usize::MAX);
+    let points: Vec<Vec<f32>> = documents
+        .iter()
+        .map(|(_, chunks)| mean_chunk(&chunks.decode()))
+        .collect();
+    let dimension = points.iter().map(Vec::len).max().unwrap_or(0);
+    if dims > dimension && !documents.is_empty() {
+        bail!(
+            "can't project embeddings of {} dimensions to {}",
+            dimension,
+            dims
+        );
+    }
+
+    let projection = match method {
+        ProjectionMethod::Pca => Projection::pca(&points, dims),
+        ProjectionMethod::Random { seed } => Projection::random(dimension, dims, seed),
+    };
+    let coordinates = documents
+        .into_iter()
+        .zip(&points)
+        .map(|((document, _), point)| (document, projection.apply(point)))
+        .collect();
+
+    Ok((projection, coordinates))
+}
+
+// A symmetric matrix of "size" rows, with "value" only computed for j >= i.
+fn symmetric(size: usize, value: impl Fn(usize, usize) -> f64) -> Vec<Vec<f64>> {
+    let upper: Vec<Vec<f64>> = (0..size)
+        .map(|i| (i..size).map(|j| value(i, j)).collect())
+        .collect();
+
+    (0..size)
+        .map(|i| {
+            (0..size)
+                .map(|j| upper[i.min(j)][i.max(j) - i.min(j)])
+                .collect()
+        })
+        .collect()
+}
+
+fn times(matrix: &[Vec<f64>], vector: &[f64]) -> Vec<f64> {
+    matrix.iter().map(|row| dot(row, vector)).collect()
+}
+
+// The top "count" eigenvectors of the symmetric positive semidefinite matrix,
+// with their eigenvalues, by subspace iteration: a basis is multiplied by the
+// matrix and orthonormalized until the eigenvalues it yields settle. Vectors
+// the matrix sends to nothing are replaced by random ones. The start is seeded
+// so that the result is deterministic.
+fn eigenvectors(matrix: &[Vec<f64>], count: usize) -> Vec<(f64, Vec<f64>)> {
+    let size = matrix.len();
+    let mut random = Random::new(0);
+    let mut basis = vec![];
+    complete(&mut basis, size, count, &mut random);
+
+    let mut values = vec![f64::INFINITY; count];
+    for _ in 0..PCA_ITERATIONS {
+        let images: Vec<Vec<f64>> = basis.iter().map(|vector| times(matrix, vector)).collect();
+        // Rayleigh quotients of the orthonormal basis.
+        let next: Vec<f64> = basis
+            .iter()
+            .zip(&images)
+            .map(|(vector, image)| dot(vector, image))
+            .collect();
+        let largest = next.iter().copied().fold(0.0, f64::max);
+        let settled = next
+            .iter()
+            .zip(&values)
+            .all(|(next, value)| (next - value).abs() <= PCA_TOLERANCE * largest);
+
+        // What's left of the images past rounding errors.
+        let norms: Vec<f64> = images
+            .iter()
+            .map(|image| dot(image, image).sqrt())
+            .collect();
+        let cutoff = PCA_TOLERANCE * norms.iter().copied().fold(0.0, f64::max);
+        basis = images
+            .into_iter()
+            .zip(norms)
+            .filter(|(_, norm)| *norm > cutoff)
+            .map(|(image, _)| image)
+            .collect();
+        orthonormalize(&mut basis);
+        basis.retain(|vector| dot(vector, vector) > 0.0);
+        complete(&mut basis, size, count, &mut random);
+        values = next;
+        if settled {
+            break;
+        }
+    }
+
+    basis
+        .into_iter()
+        .map(|vector| (dot(&vector, &times(matrix, &vector)), vector))
+        .collect()
+}
+
+// Adds random vectors to the orthonormal basis until it has "count" of them,
+// at most "size".
+fn complete(basis: &mut Vec<Vec<f64>>, size: usize, count: usize, random: &mut Random) {
+    while basis.len() < count.min(size) {
+        basis.push((0..size).map(|_| random.next_f64() - 0.5).collect());
+        orthonormalize_last(basis);
+        if dot(basis.last().unwrap(), basis.last().unwrap()) == 0.0 {
+            basis.pop();
+        }
+    }
+}
+
+// Gram-Schmidt, in order. Vectors that vanish are left at zero.
+fn orthonormalize(basis: &mut [Vec<f64>]) {
+    for i in 1..=basis.len() {
+        orthonormalize_last(&mut basis[..i]);
+    }
+}
+
+// Removes the other vectors, orthonormal, from the last one and normalizes it.
+// The last one is left at zero if little more than rounding errors remain.
+fn orthonormalize_last(basis: &mut [Vec<f64>]) {
+    let Some((last, others)) = basis.split_last_mut() else {
+        return;
+    };
+    let before = dot(last, last).sqrt();
+    for other in others.iter() {
+        let projection = dot(last, other);
+        for (x, y) in last.iter_mut().zip(other) {
+            *x -= projection * y;
+        }
+    }
+    let norm = dot(last, last).sqrt();
+    if norm > PCA_TOLERANCE * before {
+        last.iter_mut().for_each(|x| *x /= norm);
+    } else {
+        last.iter_mut().for_each(|x| *x = 0.0);
+    }
+}
+
+fn signed(component: Vec<f64>) -> Vec<f32> {
+    let largest = component
+        .iter()
+        .copied()
+        .max_by(|a, b| a.abs().total_cmp(&b.abs()))
+        .unwrap_or(0.0);
+    let sign = if largest < 0.0 { -1.0 } else { 1.0 };
+
+    component.into_iter().map(|x| (x * sign) as f32).collect()
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+// Standard normal sample, with the Box-Muller transform.
+fn gaussian(random: &mut Random) -> f64 {
+    let u = 1.0 - random.next_f64();
+    let v = random.next_f64();
+
+    (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+}
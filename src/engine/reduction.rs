@@ -0,0 +1,117 @@
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+use super::projection::Projection;
+use super::segment::Entry;
+use super::tree::IndexOptions;
+use super::validation::normalize;
+
+// How embeddings are reduced to fewer dimensions before they are indexed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReductionMethod {
+    // Keeps the first dimensions and renormalizes the result to unit length,
+    // for Matryoshka embeddings.
+    Truncate,
+    // Projects onto the principal components of the first resources indexed.
+    // The components are stored with the index.
+    Pca,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reduction {
+    pub dims: usize,
+    pub method: ReductionMethod,
+}
+
+// Most chunks the principal components are learned from. Larger inputs are
+// sampled evenly, which keeps indexing time in check.
+const PCA_SAMPLE: usize = 1024;
+
+// The principal components of the entries, if the options reduce with PCA.
+// Fails with fewer chunks than dimensions to reduce to, which can't span them.
+pub fn learn(options: &IndexOptions, entries: &[Entry]) -> anyhow::Result<Option<Projection>> {
+    let Some(reduction) = options.reduction else {
+        return Ok(None);
+    };
+    if reduction.method != ReductionMethod::Pca {
+        return Ok(None);
+    }
+
+    let chunks: Vec<&Vec<f32>> = entries.iter().flat_map(|(_, _, chunks)| chunks).collect();
+    if chunks.is_empty() {
+        return Ok(None);
+    }
+    if chunks.len() < reduction.dims {
+        bail!(
+            "PCA to {} dimensions needs at least {} embeddings, found {}",
+            reduction.dims,
+            reduction.dims,
+            chunks.len()
+        );
+    }
+    let step = chunks.len().div_ceil(PCA_SAMPLE);
+    let sample: Vec<Vec<f32>> = chunks
+        .into_iter()
+        .step_by(step)
+        .map(|chunk| chunk.to_vec())
+        .collect();
+
+    Ok(Some(Projection::pca(&sample, reduction.dims)))
+}
+
+// Reduces every chunk of the entries. Entries are left as they are without a
+// reduction.
+pub fn reduce_entries(
+    options: &IndexOptions,
+    projection: Option<&Projection>,
+    mut entries: Vec<Entry>,
+) -> Vec<Entry> {
+    if options.reduction.is_none() {
+        return entries;
+    }
+
+    for (_, _, chunks) in entries.iter_mut() {
+        for chunk in chunks.iter_mut() {
+            *chunk = reduce_chunk(options, projection, chunk).into_owned();
+        }
+    }
+
+    entries
+}
+
+// The query in the reduced space of the index. It is normalized first if the
+// index normalizes its embeddings, as they were before they were reduced.
+pub fn reduce<'a>(
+    options: &IndexOptions,
+    projection: Option<&Projection>,
+    query: &'a [f32],
+) -> Cow<'a, [f32]> {
+    if !options.normalize {
+        return reduce_chunk(options, projection, query);
+    }
+
+    let normalized = normalize(query);
+    Cow::Owned(reduce_chunk(options, projection, &normalized).into_owned())
+}
+
+// The chunk, already validated, in the reduced space of the index.
+fn reduce_chunk<'a>(
+    options: &IndexOptions,
+    projection: Option<&Projection>,
+    embeddings: &'a [f32],
+) -> Cow<'a, [f32]> {
+    let Some(reduction) = options.reduction else {
+        return Cow::Borrowed(embeddings);
+    };
+
+    match (reduction.method, projection) {
+        (ReductionMethod::Truncate, _) => Cow::Owned(normalize(
+            &embeddings[..reduction.dims.min(embeddings.len())],
+        )),
+        (ReductionMethod::Pca, Some(projection)) => Cow::Owned(projection.apply(embeddings)),
+        // Nothing was indexed yet, so there is nothing to compare with.
+        (ReductionMethod::Pca, None) => Cow::Borrowed(embeddings),
+    }
+}
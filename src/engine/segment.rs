@@ -0,0 +1,460 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+};
+
+use super::engine::{Document, SearchOptions};
+use super::parallel;
+use super::precision::{Embeddings, Precision};
+use super::tree::{IndexOptions, IndexWidth, Tree};
+
+pub type Point = [f32; 768];
+
+// Embeddings of a document, one per chunk. Most documents have a single chunk.
+pub type Chunks = Vec<Vec<f32>>;
+
+// A document together with the embeddings it was indexed with.
+pub type Entry = (u64, Document, Chunks);
+
+pub fn point(embeddings: &[f32]) -> Point {
+    let mut embeddings = embeddings.to_owned();
+    embeddings.resize(768, 0.0);
+
+    embeddings.try_into().unwrap()
+}
+
+// Squared euclidean distance. Coordinates past the end of the shorter slice are
+// zero, like the padding added by `point`.
+pub fn squared_euclidean(a: &[f32], b: &[f32]) -> f32 {
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    let shared: f32 = short.iter().zip(long).map(|(x, y)| (x - y) * (x - y)).sum();
+    let rest: f32 = long[short.len()..].iter().map(|x| x * x).sum();
+
+    shared + rest
+}
+
+pub fn sort_by_distance(neighbors: &mut [(f32, u64)]) {
+    neighbors.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+}
+
+// Nearest items found so far, closest first.
+pub struct Candidates<T> {
+    n: usize,
+    pub points: Vec<(f32, T)>,
+}
+
+impl<T> Candidates<T> {
+    pub fn new(n: usize) -> Candidates<T> {
+        Candidates { n, points: vec![] }
+    }
+
+    pub fn worst(&self) -> f32 {
+        if self.points.len() < self.n {
+            f32::INFINITY
+        } else {
+            self.points[self.n - 1].0
+        }
+    }
+
+    pub fn push(&mut self, distance: f32, item: T) {
+        if distance >= self.worst() {
+            return;
+        }
+        let at = self.points.partition_point(|(d, _)| *d <= distance);
+        self.points.insert(at, (distance, item));
+        self.points.truncate(self.n);
+    }
+}
+
+// Mean of the chunks of a document, to represent it by a single vector.
+pub fn mean_chunk(chunks: &[Vec<f32>]) -> Vec<f32> {
+    let dimension = chunks.iter().map(Vec::len).max().unwrap_or(0);
+    let mut mean = vec![0.0; dimension];
+    for chunk in chunks {
+        for (sum, x) in mean.iter_mut().zip(chunk) {
+            *sum += x / chunks.len() as f32;
+        }
+    }
+
+    mean
+}
+
+// An immutable part of the index. The tree is built once and never modified:
+// removals are recorded as tombstones and skipped at query time until the
+// segment gets compacted, so the tree never becomes unbalanced.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub tree: Tree,
+    pub data: HashMap<u64, Document>,
+    pub embeddings: HashMap<u64, Embeddings>,
+    pub tombstones: HashSet<u64>,
+    // Precision the embeddings are stored with.
+    pub precision: Precision,
+}
+
+// Every chunk is a point of its own, all pointing to the same document. They're
+// sorted first, so that the same embeddings always give the same tree, whatever
+// the order of the hash map.
+fn tree(embeddings: &HashMap<u64, Embeddings>, options: &IndexOptions) -> anyhow::Result<Tree> {
+    let mut points: Vec<(u64, u32)> = embeddings
+        .iter()
+        .flat_map(|(id, embeddings)| (0..embeddings.len() as u32).map(move |i| (*id, i)))
+        .collect();
+    points.sort_unstable();
+
+    let chunks: Vec<(&Embeddings, usize)> = points
+        .iter()
+        .map(|(id, chunk)| (&embeddings[id], *chunk as usize))
+        .collect();
+    let dimensions = chunks
+        .iter()
+        .map(|(embeddings, chunk)| embeddings.dimension(*chunk))
+        .max()
+        .unwrap_or(0);
+
+    Tree::build(points, dimensions, options, |point, dimension| {
+        let (embeddings, chunk) = chunks[point];
+        embeddings.coordinate(chunk, dimension)
+    })
+}
+
+impl Segment {
+    pub fn build(entries: Vec<Entry>, options: &IndexOptions) -> anyhow::Result<Segment> {
+        let mut data = HashMap::with_capacity(entries.len());
+        let mut embeddings = HashMap::with_capacity(entries.len());
+
+        for (id, document, chunks) in entries {
+            // Ids are content hashes, so a duplicate is the same document.
+            if data.insert(id, document).is_some() {
+                continue;
+            }
+            embeddings.insert(id, options.precision.store(chunks));
+        }
+
+        Ok(Segment {
+            tree: tree(&embeddings, options)?,
+            data,
+            embeddings,
+            tombstones: HashSet::new(),
+            precision: options.precision,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    // Marks a live document as deleted. The point stays in the tree.
+    pub fn delete(&mut self, id: &u64) -> bool {
+        if self.data.remove(id).is_none() {
+            return false;
+        }
+        self.embeddings.remove(id);
+        self.tombstones.insert(*id);
+
+        true
+    }
+
+    // Nearest k distinct documents allowed by the options, each with the distance
+    // to its closest chunk. The tree is walked nearest side first, pruning the
+    // sides further than the k-th document found so far. Removed and filtered
+    // documents are skipped as they are met, and every document is scored once,
+    // by its closest chunk.
+    pub fn search(&self, query: &Point, k: usize, options: &SearchOptions) -> Vec<(f32, u64)> {
+        if k == 0 || self.data.is_empty() {
+            return vec![];
+        }
+
+        let mut candidates = Candidates::new(k);
+        let mut seen = HashSet::new();
+        self.tree.walk(query, |points| {
+            for (id, _) in points {
+                if !seen.insert(*id) {
+                    continue;
+                }
+                // Removed documents have no embeddings left.
+                let Some(embeddings) = self.embeddings.get(id) else {
+                    continue;
+                };
+                if options.is_filtered()
+                    && !self
+                        .data
+                        .get(id)
+                        .is_some_and(|document| options.allows(&document.id))
+                {
+                    continue;
+                }
+                candidates.push(embeddings.closest(query).0, *id);
+            }
+            candidates.worst()
+        });
+
+        candidates.points
+    }
+
+    // Live documents with a chunk within "radius", in squared euclidean distance.
+    pub fn within(&self, query: &Point, radius: f32) -> Vec<(f32, u64)> {
+        let mut found = vec![];
+        self.tree.walk(query, |points| {
+            for (id, chunk) in points {
+                // Removed documents have no embeddings left.
+                let Some(embeddings) = self.embeddings.get(id) else {
+                    continue;
+                };
+                let distance = embeddings.distance(*chunk as usize, query);
+                if distance <= radius {
+                    found.push((distance, *id));
+                }
+            }
+            radius
+        });
+
+        found
+    }
+
+    pub fn into_entries(self) -> impl Iterator<Item = Entry> {
+        let Segment {
+            data,
+            mut embeddings,
+            ..
+        } = self;
+
+        data.into_iter().map(move |(id, document)| {
+            let chunks = embeddings.remove(&id).map(Embeddings::into_chunks);
+            (id, document, chunks.unwrap_or_default())
+        })
+    }
+}
+
+// How a segment is written. The embeddings are packed in the precision they are
+// held in, and the tree is left out: only its options are written, and it's
+// rebuilt from the embeddings when the segment is read.
+#[derive(Serialize)]
+struct Written<'a> {
+    data: &'a HashMap<u64, Document>,
+    packed: Packed,
+    tombstones: &'a HashSet<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Packed {
+    precision: Precision,
+    bucket_size: usize,
+    index: IndexWidth,
+    embeddings: HashMap<u64, Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct Read {
+    data: HashMap<u64, Document>,
+    packed: Packed,
+    tombstones: HashSet<u64>,
+}
+
+impl Serialize for Segment {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let options = self.tree.options();
+        let embeddings = self
+            .embeddings
+            .iter()
+            .map(|(id, embeddings)| (*id, embeddings.pack()));
+        let written = Written {
+            data: &self.data,
+            packed: Packed {
+                precision: self.precision,
+                bucket_size: options.bucket_size,
+                index: options.index,
+                embeddings: embeddings.collect(),
+            },
+            tombstones: &self.tombstones,
+        };
+
+        written.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Segment {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Segment, D::Error> {
+        let read = Read::deserialize(deserializer)?;
+        read.into_segment().map_err(de::Error::custom)
+    }
+}
+
+impl Read {
+    fn into_segment(self) -> anyhow::Result<Segment> {
+        let packed = self.packed;
+        let precision = packed.precision;
+        let mut embeddings = HashMap::with_capacity(packed.embeddings.len());
+        for (id, chunks) in packed.embeddings {
+            embeddings.insert(id, precision.unpack(&chunks)?);
+        }
+        let options = IndexOptions {
+            bucket_size: packed.bucket_size,
+            index: packed.index,
+            ..IndexOptions::default()
+        };
+        Ok(Segment {
+            tree: tree(&embeddings, &options)?,
+            data: self.data,
+            embeddings,
+            tombstones: self.tombstones,
+            precision,
+        })
+    }
+}
+
+// The mutable head of the index. New documents are appended here and searched
+// exhaustively; once it reaches capacity it is flushed into a new segment. Its
+// embeddings are held in the precision of the index, like those of segments.
+#[derive(Debug, Clone, Default)]
+pub struct WriteAheadLog {
+    pub data: HashMap<u64, Document>,
+    pub embeddings: HashMap<u64, Embeddings>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PackedLog {
+    precision: Precision,
+    embeddings: HashMap<u64, Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct WrittenLog<'a> {
+    data: &'a HashMap<u64, Document>,
+    packed: PackedLog,
+}
+
+// Logs of version 1 indexes hold their embeddings as arrays of f32.
+#[derive(Deserialize)]
+struct ReadLog {
+    data: HashMap<u64, Document>,
+    #[serde(default)]
+    packed: Option<PackedLog>,
+    #[serde(default)]
+    embeddings: HashMap<u64, Chunks>,
+}
+
+impl Serialize for WriteAheadLog {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Every embedding is stored in the precision of the index.
+        let precision = self
+            .embeddings
+            .values()
+            .next()
+            .map_or(Precision::F32, Embeddings::precision);
+        let embeddings = self
+            .embeddings
+            .iter()
+            .map(|(id, embeddings)| (*id, embeddings.pack()));
+        let written = WrittenLog {
+            data: &self.data,
+            packed: PackedLog {
+                precision,
+                embeddings: embeddings.collect(),
+            },
+        };
+
+        written.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for WriteAheadLog {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<WriteAheadLog, D::Error> {
+        let read = ReadLog::deserialize(deserializer)?;
+        let embeddings = match read.packed {
+            Some(PackedLog {
+                precision,
+                embeddings,
+            }) => embeddings
+                .into_iter()
+                .map(|(id, chunks)| Ok((id, precision.unpack(&chunks)?)))
+                .collect::<anyhow::Result<_>>()
+                .map_err(de::Error::custom)?,
+            None => read
+                .embeddings
+                .into_iter()
+                .map(|(id, chunks)| (id, Embeddings::F32(chunks)))
+                .collect(),
+        };
+
+        Ok(WriteAheadLog {
+            data: read.data,
+            embeddings,
+        })
+    }
+}
+
+impl WriteAheadLog {
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn insert(&mut self, id: u64, document: Document, embeddings: Embeddings) {
+        self.data.insert(id, document);
+        self.embeddings.insert(id, embeddings);
+    }
+
+    // Stores the embeddings in "precision", for logs read from indexes that
+    // held them in f32.
+    pub fn store(&mut self, precision: Precision) {
+        for embeddings in self.embeddings.values_mut() {
+            if embeddings.precision() != precision {
+                let chunks = std::mem::replace(embeddings, Embeddings::F32(vec![])).into_chunks();
+                *embeddings = precision.store(chunks);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: &u64) -> bool {
+        self.embeddings.remove(id);
+        self.data.remove(id).is_some()
+    }
+
+    pub fn search(&self, query: &Point, k: usize, options: &SearchOptions) -> Vec<(f32, u64)> {
+        let embeddings: Vec<(&u64, &Embeddings)> = self
+            .embeddings
+            .iter()
+            .filter(|(id, _)| {
+                !options.is_filtered()
+                    || self
+                        .data
+                        .get(id)
+                        .is_some_and(|document| options.allows(&document.id))
+            })
+            .collect();
+        let mut neighbors = parallel::map(&embeddings, |(id, embeddings)| {
+            (embeddings.closest(query).0, **id)
+        });
+
+        sort_by_distance(&mut neighbors);
+        neighbors.truncate(k);
+        neighbors
+    }
+
+    pub fn within(&self, query: &Point, radius: f32) -> Vec<(f32, u64)> {
+        self.embeddings
+            .iter()
+            .flat_map(|(id, embeddings)| {
+                (0..embeddings.len()).map(move |chunk| (embeddings.distance(chunk, query), *id))
+            })
+            .filter(|(distance, _)| *distance <= radius)
+            .collect()
+    }
+
+    pub fn drain(&mut self) -> Vec<Entry> {
+        let mut embeddings = std::mem::take(&mut self.embeddings);
+        std::mem::take(&mut self.data)
+            .into_iter()
+            .map(|(id, document)| {
+                let chunks = embeddings.remove(&id).map(Embeddings::into_chunks);
+                (id, document, chunks.unwrap_or_default())
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::{HashMap, HashSet};
+
+use super::boost::Metadata;
+use super::changes::ChangeLog;
+use super::duplicates::DuplicatePolicy;
+use super::engine::{Document, Index};
+use super::format::FORMAT_VERSION;
+use super::precision::{Embeddings, Precision};
+use super::projection::Projection;
+use super::reduction::ReductionMethod;
+use super::segment::{Segment, WriteAheadLog};
+use super::tree::IndexOptions;
+
+pub const METRIC: &str = "squared_euclidean";
+
+#[derive(Debug, Clone, Default)]
+pub struct SegmentStats {
+    pub documents: usize,
+    pub tombstones: usize,
+    pub leaves: usize,
+    pub stems: usize,
+    pub min_depth: usize,
+    pub max_depth: usize,
+    // Average share of a bucket that holds points, between 0 and 1.
+    pub bucket_fill: f32,
+    // Ids of documents in "data" that can't be found in the tree.
+    pub missing_from_tree: Vec<String>,
+    // Number of items in the tree that are neither in "data" nor tombstoned.
+    pub missing_from_data: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub dimension: usize,
+    pub metric: &'static str,
+    pub documents: usize,
+    pub tombstones: usize,
+    pub wal: usize,
+    pub segments: Vec<SegmentStats>,
+    pub memory_bytes: usize,
+    // Estimated length of the index serialized by `serialize_index`, before any
+    // compression.
+    pub serialized_bytes: usize,
+}
+
+// Characters taken by a serialized float, which depend on its digits. Integers
+// like timestamps are written exactly, other values are assumed to take about
+// as many characters as the shortest representation of a typical f32 or f64.
+const F32_SIZE: usize = 11;
+const F64_SIZE: usize = 19;
+
+fn integer_size(value: u64) -> usize {
+    value
+        .checked_ilog10()
+        .map_or(1, |digits| digits as usize + 1)
+}
+
+fn float_size(value: f64, typical: usize) -> usize {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        // Written with a trailing ".0".
+        (value < 0.0) as usize + integer_size(value.abs() as u64) + 2
+    } else {
+        typical
+    }
+}
+
+// Quotes included. Only quotes and backslashes are counted among the escaped
+// characters: control characters are rare in documents.
+fn string_size(value: &str) -> usize {
+    let escaped = value.bytes().filter(|b| *b == b'"' || *b == b'\\').count();
+    value.len() + escaped + 2
+}
+
+// Length of an array or map of items of these lengths, with the separators.
+// Map items include their key and colon.
+fn collection_size(items: impl Iterator<Item = usize>) -> usize {
+    let (count, size) = items.fold((0usize, 0), |(count, size), item| (count + 1, size + item));
+    size + count.saturating_sub(1) + 2
+}
+
+// A map key made of a document hash.
+fn key_size(id: u64) -> usize {
+    integer_size(id) + 3
+}
+
+fn precision_size(precision: Precision) -> usize {
+    match precision {
+        Precision::Bf16 => 6,
+        _ => 5,
+    }
+}
+
+fn metadata_size(metadata: &Metadata) -> usize {
+    collection_size(
+        metadata
+            .0
+            .iter()
+            .map(|(field, value)| string_size(field) + 1 + float_size(*value, F64_SIZE)),
+    )
+}
+
+fn document_size(document: &Document) -> usize {
+    // {"id":,"title":,"url":}
+    let mut size =
+        23 + string_size(&document.id) + string_size(&document.title) + string_size(&document.url);
+    if !document.metadata.is_empty() {
+        // ,"metadata":
+        size += 12 + metadata_size(&document.metadata);
+    }
+    size
+}
+
+fn data_size(data: &HashMap<u64, Document>) -> usize {
+    collection_size(
+        data.iter()
+            .map(|(id, document)| key_size(*id) + document_size(document)),
+    )
+}
+
+// Packed embeddings: one string of hex digits per chunk.
+fn embeddings_size(embeddings: &HashMap<u64, Embeddings>, precision: Precision) -> usize {
+    collection_size(embeddings.iter().map(|(id, embeddings)| {
+        let chunks =
+            (0..embeddings.len()).map(|chunk| embeddings.dimension(chunk) * precision.digits() + 2);
+        key_size(*id) + collection_size(chunks)
+    }))
+}
+
+fn segment_size(segment: &Segment) -> usize {
+    // {"data":,"packed":{"precision":,"bucket_size":,"index":"u16","embeddings":},"tombstones":}
+    8 + data_size(&segment.data)
+        + 23
+        + precision_size(segment.precision)
+        + 15
+        + integer_size(segment.tree.bucket_size as u64)
+        + 14
+        + 14
+        + embeddings_size(&segment.embeddings, segment.precision)
+        + 1
+        + 14
+        + collection_size(segment.tombstones.iter().map(|id| integer_size(*id)))
+        + 1
+}
+
+fn wal_size(wal: &WriteAheadLog) -> usize {
+    let precision = wal
+        .embeddings
+        .values()
+        .next()
+        .map_or(Precision::F32, Embeddings::precision);
+
+    // {"data":,"packed":{"precision":,"embeddings":}}
+    8 + data_size(&wal.data)
+        + 23
+        + precision_size(precision)
+        + 14
+        + embeddings_size(&wal.embeddings, precision)
+        + 2
+}
+
+fn changes_size(changes: &ChangeLog) -> usize {
+    let entries = changes.changes.iter().map(|(id, change)| {
+        // {"created":,"changed":,"removed":}
+        let removed = change.removed.as_ref().map_or(4, document_size);
+        key_size(*id) + 34 + integer_size(change.created) + integer_size(change.changed) + removed
+    });
+
+    // {"version":,"cleared":,"truncated":,"changes":}
+    47 + integer_size(changes.version)
+        + integer_size(changes.cleared)
+        + integer_size(changes.truncated)
+        + collection_size(entries)
+}
+
+fn projection_size(projection: &Projection) -> usize {
+    let floats = |values: &[f32]| collection_size(values.iter().map(|_| F32_SIZE));
+
+    // {"mean":,"components":}
+    23 + floats(&projection.mean)
+        + collection_size(
+            projection
+                .components
+                .iter()
+                .map(|component| floats(component)),
+        )
+}
+
+// Options left to their defaults are skipped.
+fn options_size(options: &IndexOptions) -> usize {
+    // {"bucket_size":,"index":"u16"}
+    let mut size = 30 + integer_size(options.bucket_size as u64);
+    match options.duplicates {
+        DuplicatePolicy::Keep => {}
+        // ,"duplicates":"reject"
+        DuplicatePolicy::Reject => size += 22,
+        DuplicatePolicy::Merge => size += 21,
+    }
+    if options.duplicate_threshold != 0.0 {
+        // ,"duplicate_threshold":
+        size += 23 + float_size(options.duplicate_threshold as f64, F32_SIZE);
+    }
+    if let Some(reduction) = options.reduction {
+        // ,"reduction":{"dims":,"method":"pca"}
+        let method = match reduction.method {
+            ReductionMethod::Truncate => 10,
+            ReductionMethod::Pca => 5,
+        };
+        size += 32 + integer_size(reduction.dims as u64) + method;
+    }
+    if options.normalize {
+        // ,"normalize":true
+        size += 17;
+    }
+    if !options.precision.is_f32() {
+        // ,"precision":
+        size += 13 + precision_size(options.precision);
+    }
+    size
+}
+
+// The length of the JSON written by `serialize_index`, computed from the sizes
+// of its parts instead of writing it. Only floats that aren't integers, escapes
+// of control characters and the checksum are estimated, which keeps it well
+// within a percent for indexes of realistic documents.
+pub fn serialized_size(index: &Index) -> usize {
+    let segments = collection_size(index.segments.iter().map(segment_size));
+    let projection = index
+        .projection
+        .as_deref()
+        .map_or(0, |projection| 14 + projection_size(projection));
+
+    // {"options":,"segments":,"wal":,"changes":}
+    let index_size = 42
+        + options_size(&index.options)
+        + segments
+        + wal_size(&index.wal)
+        + changes_size(&index.changes)
+        + projection;
+
+    // {"version":,"checksum":,"index":}, with the longest checksum.
+    33 + integer_size(FORMAT_VERSION as u64) + 10 + index_size
+}
+
+fn segment_stats(segment: &Segment) -> SegmentStats {
+    let tree = &segment.tree;
+    let depths = tree.leaf_depths();
+    let items: HashSet<u64> = tree.points.iter().map(|(id, _)| *id).collect();
+
+    let missing_from_tree = segment
+        .data
+        .iter()
+        .filter(|(id, _)| !items.contains(id))
+        .map(|(_, document)| document.id.to_owned())
+        .collect();
+    let missing_from_data = items
+        .iter()
+        .filter(|id| !segment.data.contains_key(id) && !segment.tombstones.contains(id))
+        .count();
+
+    let bucket_fill = if tree.leaves.is_empty() {
+        0.0
+    } else {
+        tree.size() as f32 / (tree.leaves.len() * tree.bucket_size) as f32
+    };
+
+    SegmentStats {
+        documents: segment.len(),
+        tombstones: segment.tombstones.len(),
+        leaves: tree.leaves.len(),
+        stems: tree.stems.len(),
+        min_depth: depths.iter().copied().min().unwrap_or_default(),
+        max_depth: depths.iter().copied().max().unwrap_or_default(),
+        bucket_fill,
+        missing_from_tree,
+        missing_from_data,
+    }
+}
+
+// A rough estimate of the heap used by the index: tree nodes, documents and the
+// stored embeddings. Allocator and hash map overhead are not included.
+fn memory_size(index: &Index) -> usize {
+    let nodes: usize = index
+        .segments
+        .iter()
+        .map(|segment| segment.tree.memory_size())
+        .sum();
+
+    let documents = index
+        .segments
+        .iter()
+        .flat_map(|segment| segment.data.values())
+        .chain(index.wal.data.values())
+        .map(|document| document.id.len() + document.title.len() + document.url.len())
+        .sum::<usize>();
+
+    let embeddings = index
+        .segments
+        .iter()
+        .flat_map(|segment| segment.embeddings.values())
+        .chain(index.wal.embeddings.values())
+        .map(Embeddings::memory_size)
+        .sum::<usize>();
+
+    nodes + documents + embeddings
+}
+
+// Length of the longest embeddings in the index.
+pub fn dimension(index: &Index) -> usize {
+    index
+        .segments
+        .iter()
+        .flat_map(|segment| segment.embeddings.values())
+        .chain(index.wal.embeddings.values())
+        .flat_map(|embeddings| (0..embeddings.len()).map(move |i| embeddings.dimension(i)))
+        .max()
+        .unwrap_or_default()
+}
+
+pub fn stats(index: &Index) -> anyhow::Result<Stats> {
+    let segments: Vec<SegmentStats> = index.segments.iter().map(segment_stats).collect();
+
+    Ok(Stats {
+        dimension: dimension(index),
+        metric: METRIC,
+        documents: super::size(index),
+        tombstones: segments.iter().map(|segment| segment.tombstones).sum(),
+        wal: index.wal.len(),
+        memory_bytes: memory_size(index),
+        serialized_bytes: serialized_size(index),
+        segments,
+    })
+}
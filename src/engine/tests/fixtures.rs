@@ -1,3 +1,5 @@
+#![allow(clippy::excessive_precision)]
+
 use rstest::fixture;
 use crate::{Resource, EmbeddedResource};
 
@@ -27,11 +29,28 @@ pub fn resource_fixture() -> Resource {
             title: content.get(i).unwrap().to_string(),
             url: "".to_owned(),
             embeddings: x.to_vec(),
+            chunks: None,
+            metadata: None,
         })
         .collect();
     Resource { embeddings }
 }
 
+// Builds a resource with no title, url or metadata out of its embeddings.
+pub type Embedded = fn(&str, Vec<f32>) -> EmbeddedResource;
+
+#[fixture]
+pub fn embedded_fixture() -> Embedded {
+    |id, embeddings| EmbeddedResource {
+        id: id.to_owned(),
+        title: "".to_owned(),
+        url: "".to_owned(),
+        embeddings,
+        chunks: None,
+        metadata: None,
+    }
+}
+
 pub static CONTENT: [&str; 6] = [
     "The Amazon rainforest,[a] also called Amazon jungle or Amazonia, is a moist broadleaf tropical rainforest in the",
     "in the Amazon biome that covers most of the Amazon basin of South America. This basin encompasses 7,000,000 km2",
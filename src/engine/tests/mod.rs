@@ -1,15 +1,18 @@
 mod fixtures;
 
 use crate::engine;
+use crate::engine::random::Random;
 use crate::{EmbeddedResource, Resource};
 use fixtures::*;
 use rstest::*;
+use std::collections::BTreeMap;
 
 #[rstest]
 fn it_indexes_embeddings(resource_fixture: Resource) {
-    let index = engine::index(resource_fixture).unwrap();
+    let index = engine::index(resource_fixture, &Default::default()).unwrap();
 
-    assert_eq!(index.tree.size(), 6);
+    assert_eq!(index.segments.len(), 1);
+    assert_eq!(index.segments[0].tree.size(), 6);
 }
 
 #[rstest]
@@ -18,16 +21,16 @@ fn it_returns_vector_search_result(
     question_fixture: [f32; 768],
     content_fixture: [&'static str; 6],
 ) {
-    let index = engine::index(resource_fixture).unwrap();
+    let index = engine::index(resource_fixture, &Default::default()).unwrap();
     let query = engine::Query::Embeddings(question_fixture.to_vec());
-    let result = engine::search(&index, &query, 6).unwrap();
+    let result = engine::search(&index, &query, 6, &Default::default()).unwrap();
 
-    assert_eq!(result.get(0).unwrap().title, content_fixture[0]);
-    assert_eq!(result.get(1).unwrap().title, content_fixture[1]);
-    assert_eq!(result.get(2).unwrap().title, content_fixture[2]);
-    assert_eq!(result.get(3).unwrap().title, content_fixture[4]);
-    assert_eq!(result.get(4).unwrap().title, content_fixture[5]);
-    assert_eq!(result.get(5).unwrap().title, content_fixture[3]);
+    assert_eq!(result.first().unwrap().document.title, content_fixture[0]);
+    assert_eq!(result.get(1).unwrap().document.title, content_fixture[1]);
+    assert_eq!(result.get(2).unwrap().document.title, content_fixture[2]);
+    assert_eq!(result.get(3).unwrap().document.title, content_fixture[4]);
+    assert_eq!(result.get(4).unwrap().document.title, content_fixture[5]);
+    assert_eq!(result.get(5).unwrap().document.title, content_fixture[3]);
 }
 
 #[rstest]
@@ -36,18 +39,22 @@ fn it_adds_embeddings_to_index(
     content_fixture: [&'static str; 6],
     embedding_fixture: [[f32; 768]; 6],
 ) {
-    let mut index = engine::index(resource_fixture).unwrap();
+    let mut index = engine::index(resource_fixture, &Default::default()).unwrap();
     let addition = Resource {
         embeddings: vec![EmbeddedResource {
             id: "5".to_owned(),
             title: content_fixture.get(5).unwrap().to_string(),
             url: "".to_owned(),
             embeddings: embedding_fixture[5].to_vec(),
+            chunks: None,
+            metadata: None,
         }],
     };
 
-    engine::add(&mut index, &addition);
-    assert_eq!(index.tree.size(), 7);
+    engine::add(&mut index, &addition).unwrap();
+    assert_eq!(engine::size(&index), 6);
+    assert_eq!(index.wal.len(), 1);
+    assert_eq!(index.segments[0].tombstones.len(), 1);
 }
 
 #[rstest]
@@ -56,35 +63,2408 @@ fn it_removes_embeddings_from_index(
     content_fixture: [&'static str; 6],
     embedding_fixture: [[f32; 768]; 6],
 ) {
-    let mut index = engine::index(resource_fixture).unwrap();
+    let mut index = engine::index(resource_fixture, &Default::default()).unwrap();
     let target = Resource {
         embeddings: vec![EmbeddedResource {
             id: "1".to_owned(),
             title: content_fixture.get(1).unwrap().to_string(),
             url: "".to_owned(),
             embeddings: embedding_fixture[1].to_vec(),
+            chunks: None,
+            metadata: None,
         }],
     };
 
     engine::remove(&mut index, &target);
-    assert_eq!(index.tree.size(), 5);
+    assert_eq!(engine::size(&index), 5);
+    assert_eq!(index.segments[0].tombstones.len(), 1);
 }
 
 #[rstest]
 fn it_clears_all_embeddings_from_index(resource_fixture: Resource) {
-    let mut index = engine::index(resource_fixture).unwrap();
-    assert_eq!(index.tree.size(), 6);
-    assert_eq!(index.data.len(), 6);
+    let mut index = engine::index(resource_fixture, &Default::default()).unwrap();
+    assert_eq!(engine::size(&index), 6);
 
     engine::clear(&mut index);
-    assert_eq!(index.tree.size(), 0);
-    assert_eq!(index.data.len(), 0);
+    assert_eq!(engine::size(&index), 0);
+    assert!(index.segments.is_empty());
+    assert!(index.wal.is_empty());
 }
 
 #[rstest]
 fn it_returns_the_size_of_index(resource_fixture: Resource) {
-    let index = engine::index(resource_fixture).unwrap();
-    assert_eq!(index.tree.size(), 6);
-    assert_eq!(index.data.len(), 6);
+    let index = engine::index(resource_fixture, &Default::default()).unwrap();
+    assert_eq!(index.segments[0].tree.size(), 6);
+    assert_eq!(index.segments[0].data.len(), 6);
+    assert_eq!(engine::size(&index), 6);
+}
+
+#[rstest]
+fn it_hides_removed_embeddings_from_search_result(
+    resource_fixture: Resource,
+    question_fixture: [f32; 768],
+    content_fixture: [&'static str; 6],
+    embedding_fixture: [[f32; 768]; 6],
+) {
+    let mut index = engine::index(resource_fixture, &Default::default()).unwrap();
+    let target = Resource {
+        embeddings: vec![EmbeddedResource {
+            id: "0".to_owned(),
+            title: content_fixture.first().unwrap().to_string(),
+            url: "".to_owned(),
+            // Removal goes by document, so the embeddings don't have to match.
+            embeddings: embedding_fixture[3].to_vec(),
+            chunks: None,
+            metadata: None,
+        }],
+    };
+
+    engine::remove(&mut index, &target);
+    let query = engine::Query::Embeddings(question_fixture.to_vec());
+    let result = engine::search(&index, &query, 6, &Default::default()).unwrap();
+
+    assert_eq!(result.len(), 5);
+    assert_eq!(result.first().unwrap().document.title, content_fixture[1]);
+    assert_eq!(result.get(1).unwrap().document.title, content_fixture[2]);
+}
+
+#[rstest]
+fn it_searches_across_segments_and_write_ahead_log(
+    question_fixture: [f32; 768],
+    content_fixture: [&'static str; 6],
+    embedding_fixture: [[f32; 768]; 6],
+) {
+    let mut index = engine::index(Resource { embeddings: vec![] }, &Default::default()).unwrap();
+    for (i, embeddings) in embedding_fixture.iter().enumerate() {
+        let addition = Resource {
+            embeddings: vec![EmbeddedResource {
+                id: i.to_string(),
+                title: content_fixture.get(i).unwrap().to_string(),
+                url: "".to_owned(),
+                embeddings: embeddings.to_vec(),
+                chunks: None,
+                metadata: None,
+            }],
+        };
+        engine::add(&mut index, &addition).unwrap();
+        if i % 2 == 1 {
+            engine::flush(&mut index).unwrap();
+        }
+    }
+    assert_eq!(index.segments.len(), 3);
+
+    let query = engine::Query::Embeddings(question_fixture.to_vec());
+    let result = engine::search(&index, &query, 6, &Default::default()).unwrap();
+
+    assert_eq!(result.first().unwrap().document.title, content_fixture[0]);
+    assert_eq!(result.get(1).unwrap().document.title, content_fixture[1]);
+    assert_eq!(result.get(2).unwrap().document.title, content_fixture[2]);
+    assert_eq!(result.get(3).unwrap().document.title, content_fixture[4]);
+    assert_eq!(result.get(4).unwrap().document.title, content_fixture[5]);
+    assert_eq!(result.get(5).unwrap().document.title, content_fixture[3]);
+}
+
+#[rstest]
+fn it_flushes_write_ahead_log_into_segment(embedding_fixture: [[f32; 768]; 6]) {
+    let mut index = engine::index(Resource { embeddings: vec![] }, &Default::default()).unwrap();
+    let addition = Resource {
+        embeddings: (0..engine::WAL_CAPACITY)
+            .map(|i| EmbeddedResource {
+                id: i.to_string(),
+                title: "".to_owned(),
+                url: "".to_owned(),
+                embeddings: embedding_fixture[i % 6]
+                    .iter()
+                    .map(|x| x + i as f32 * 1e-4)
+                    .collect(),
+                chunks: None,
+                metadata: None,
+            })
+            .collect(),
+    };
+
+    engine::add(&mut index, &addition).unwrap();
+    assert!(index.wal.is_empty());
+    assert_eq!(index.segments.len(), 1);
+    assert_eq!(engine::size(&index), engine::WAL_CAPACITY);
+}
+
+#[rstest]
+fn it_compacts_segments_and_drops_tombstones(
+    resource_fixture: Resource,
+    content_fixture: [&'static str; 6],
+    embedding_fixture: [[f32; 768]; 6],
+) {
+    let mut index = engine::index(resource_fixture, &Default::default()).unwrap();
+    let target = Resource {
+        embeddings: vec![EmbeddedResource {
+            id: "1".to_owned(),
+            title: content_fixture.get(1).unwrap().to_string(),
+            url: "".to_owned(),
+            embeddings: embedding_fixture[1].to_vec(),
+            chunks: None,
+            metadata: None,
+        }],
+    };
+    engine::remove(&mut index, &target);
+    engine::add(&mut index, &target).unwrap();
+    engine::flush(&mut index).unwrap();
+    assert_eq!(index.segments.len(), 2);
+
+    engine::compact(&mut index).unwrap();
+    assert_eq!(index.segments.len(), 1);
+    assert_eq!(index.segments[0].tree.size(), 6);
+    assert!(index.segments[0].tombstones.is_empty());
+    assert_eq!(engine::size(&index), 6);
+}
+
+#[rstest]
+fn it_returns_stats_of_index(resource_fixture: Resource) {
+    let resource = || Resource {
+        embeddings: resource_fixture.embeddings.clone(),
+    };
+    let index = engine::index(resource(), &Default::default()).unwrap();
+    let stats = engine::stats(&index).unwrap();
+
+    assert_eq!(stats.dimension, 768);
+    assert_eq!(stats.metric, "squared_euclidean");
+    assert_eq!(stats.documents, 6);
+    assert_eq!(stats.tombstones, 0);
+    assert_eq!(stats.segments.len(), 1);
+    assert_eq!(stats.segments[0].leaves, 1);
+    assert_eq!(stats.segments[0].max_depth, 0);
+    assert!(stats.segments[0].missing_from_tree.is_empty());
+    assert_eq!(stats.segments[0].missing_from_data, 0);
+    assert!(stats.memory_bytes > 6 * 768 * 4);
+
+    // The serialized size is estimated without serializing the index, from
+    // packed segments as well as the log, tombstones and versions.
+    let close = |index: &engine::Index| {
+        let estimated = engine::stats(index).unwrap().serialized_bytes as f32;
+        let actual = engine::serialize_index(index).unwrap().len() as f32;
+        assert!(
+            (estimated - actual).abs() <= actual * 0.01,
+            "{} for {}",
+            estimated,
+            actual
+        );
+    };
+    close(&index);
+    let options = engine::IndexOptions {
+        precision: engine::Precision::F16,
+        ..Default::default()
+    };
+    let mut updated = resource_fixture.embeddings[0].clone();
+    let removed = Resource {
+        embeddings: resource_fixture.embeddings[1..2].to_vec(),
+    };
+    let mut mixed = engine::index(resource(), &options).unwrap();
+    updated.metadata = Some(BTreeMap::from([("timestamp".to_owned(), 1.5)]));
+    engine::add(
+        &mut mixed,
+        &Resource {
+            embeddings: vec![updated],
+        },
+    )
+    .unwrap();
+    engine::remove(&mut mixed, &removed);
+    close(&mixed);
+}
+
+#[rstest]
+fn it_flags_mismatches_between_tree_and_data(resource_fixture: Resource) {
+    let mut index = engine::index(resource_fixture, &Default::default()).unwrap();
+    let segment = &mut index.segments[0];
+    let (id, _) = segment
+        .data
+        .iter()
+        .find(|(_, document)| document.id == "2")
+        .unwrap();
+    let id = *id;
+
+    // Rebuild the tree with the point of "2" pointing to an unknown item.
+    let chunks: Vec<Vec<f32>> = segment
+        .embeddings
+        .values()
+        .map(|embeddings| embeddings.decode()[0].to_owned())
+        .collect();
+    let points: Vec<((u64, u32), &[f32])> = segment
+        .embeddings
+        .keys()
+        .zip(&chunks)
+        .map(|(item, chunk)| ((if *item == id { 42 } else { *item }, 0), chunk.as_slice()))
+        .collect();
+    segment.tree = tree_of(&points);
+    let stats = engine::stats(&index).unwrap();
+
+    assert_eq!(stats.segments[0].missing_from_tree, vec!["2".to_owned()]);
+    assert_eq!(stats.segments[0].missing_from_data, 1);
+}
+
+#[rstest]
+fn it_finds_embeddings_by_id(resource_fixture: Resource, embedding_fixture: [[f32; 768]; 6]) {
+    let index = engine::index(resource_fixture, &Default::default()).unwrap();
+    let (document, embeddings) = engine::find(&index, "3").unwrap();
+
+    assert_eq!(document.id, "3");
+    assert_eq!(*embeddings.decode(), [embedding_fixture[3].to_vec()]);
+    assert!(engine::find(&index, "6").is_none());
+}
+
+#[rstest]
+fn it_lists_documents_in_pages(
+    resource_fixture: Resource,
+    content_fixture: [&'static str; 6],
+    embedding_fixture: [[f32; 768]; 6],
+) {
+    let mut index = engine::index(resource_fixture, &Default::default()).unwrap();
+    let addition = Resource {
+        embeddings: vec![EmbeddedResource {
+            id: "10".to_owned(),
+            title: content_fixture.first().unwrap().to_string(),
+            url: "".to_owned(),
+            embeddings: embedding_fixture[0].to_vec(),
+            chunks: None,
+            metadata: None,
+        }],
+    };
+    engine::add(&mut index, &addition).unwrap();
+
+    let ids: Vec<&str> = engine::documents(&index, 0, usize::MAX)
+        .into_iter()
+        .map(|(document, _)| document.id.as_str())
+        .collect();
+    assert_eq!(ids, vec!["0", "1", "10", "2", "3", "4", "5"]);
+
+    let page = engine::documents(&index, 2, 3);
+    assert_eq!(page.len(), 3);
+    assert_eq!(page[0].0.id, "10");
+    assert_eq!(*page[0].1.decode(), [embedding_fixture[0].to_vec()]);
+    assert_eq!(page[2].0.id, "3");
+    assert!(engine::documents(&index, 7, 3).is_empty());
+}
+
+#[rstest]
+fn it_searches_similar_to_indexed_document(resource_fixture: Resource) {
+    let index = engine::index(resource_fixture, &Default::default()).unwrap();
+
+    let result = engine::search_similar_to(&index, "0", 3, false).unwrap();
+    assert_eq!(result.len(), 3);
+    assert!(result.iter().all(|neighbor| neighbor.document.id != "0"));
+
+    let result = engine::search_similar_to(&index, "0", 3, true).unwrap();
+    assert_eq!(result.first().unwrap().document.id, "0");
+
+    let result = engine::search_similar_to(&index, "6", 3, false).unwrap();
+    assert!(result.is_empty());
+}
+
+#[rstest]
+fn it_returns_distinct_documents_for_chunked_embeddings(
+    question_fixture: [f32; 768],
+    embedding_fixture: [[f32; 768]; 6],
+) {
+    let chunked = |id: &str, chunks: &[usize]| EmbeddedResource {
+        id: id.to_owned(),
+        title: id.to_owned(),
+        url: "".to_owned(),
+        embeddings: vec![],
+        chunks: Some(
+            chunks
+                .iter()
+                .map(|&i| embedding_fixture[i].to_vec())
+                .collect(),
+        ),
+        metadata: None,
+    };
+    let resource = Resource {
+        embeddings: vec![
+            chunked("a", &[0, 1, 2]),
+            chunked("b", &[3, 4]),
+            chunked("c", &[5]),
+        ],
+    };
+    let mut index = engine::index(resource, &Default::default()).unwrap();
+    assert_eq!(index.segments[0].tree.size(), 6);
+    assert_eq!(engine::size(&index), 3);
+
+    let query = engine::Query::Embeddings(question_fixture.to_vec());
+    let result = engine::search(&index, &query, 3, &Default::default()).unwrap();
+    let ids: Vec<(&str, usize)> = result
+        .iter()
+        .map(|neighbor| (neighbor.document.id.as_str(), neighbor.chunk))
+        .collect();
+    assert_eq!(ids, vec![("a", 0), ("b", 1), ("c", 0)]);
+
+    // Chunks in the write-ahead log are aggregated the same way.
+    engine::add(
+        &mut index,
+        &Resource {
+            embeddings: vec![chunked("d", &[4, 1])],
+        },
+    )
+    .unwrap();
+    let result = engine::search(&index, &query, 2, &Default::default()).unwrap();
+    assert_eq!(result[1].document.id, "d");
+    assert_eq!(result[1].chunk, 1);
+}
+
+#[rstest]
+fn it_aggregates_chunk_scores(question_fixture: [f32; 768], embedding_fixture: [[f32; 768]; 6]) {
+    let chunked = |id: &str, chunks: &[usize]| EmbeddedResource {
+        id: id.to_owned(),
+        title: id.to_owned(),
+        url: "".to_owned(),
+        embeddings: vec![],
+        chunks: Some(
+            chunks
+                .iter()
+                .map(|&i| embedding_fixture[i].to_vec())
+                .collect(),
+        ),
+        metadata: None,
+    };
+    let resource = Resource {
+        embeddings: vec![chunked("a", &[0]), chunked("b", &[1, 2, 4])],
+    };
+    let index = engine::index(resource, &Default::default()).unwrap();
+    let query = engine::Query::Embeddings(question_fixture.to_vec());
+
+    let max = engine::search(&index, &query, 2, &Default::default()).unwrap();
+    assert_eq!(max[0].document.id, "a");
+
+    let options = engine::SearchOptions {
+        aggregation: engine::Aggregation::Sum,
+        top_chunks: 3,
+        ..Default::default()
+    };
+    let sum = engine::search(&index, &query, 2, &options).unwrap();
+    assert_eq!(sum[0].document.id, "b");
+    assert_eq!(sum[0].chunk, 0);
+
+    let options = engine::SearchOptions {
+        aggregation: engine::Aggregation::Mean,
+        top_chunks: 3,
+        ..Default::default()
+    };
+    let mean = engine::search(&index, &query, 2, &options).unwrap();
+    assert_eq!(mean[0].document.id, "a");
+    assert_eq!(mean.len(), 2);
+}
+
+#[rstest]
+fn it_searches_across_namespaces(
+    resource_fixture: Resource,
+    question_fixture: [f32; 768],
+    content_fixture: [&'static str; 6],
+) {
+    let mut resources = resource_fixture.embeddings;
+    let odd = resources.iter().skip(1).step_by(2).cloned().collect();
+    let even = resources.drain(..).step_by(2).collect();
+
+    let mut namespaces = engine::Namespaces::new(
+        engine::index(Resource { embeddings: even }, &Default::default()).unwrap(),
+    );
+    namespaces.insert(
+        "odd",
+        engine::index(Resource { embeddings: odd }, &Default::default()).unwrap(),
+    );
+    assert_eq!(
+        namespaces.names(),
+        vec!["default".to_owned(), "odd".to_owned()]
+    );
+
+    let query = engine::Query::Embeddings(question_fixture.to_vec());
+    let options = Default::default();
+    let result = namespaces
+        .search(&namespaces.names(), &query, 6, &options)
+        .unwrap();
+    let titles: Vec<(&str, &str)> = result
+        .iter()
+        .map(|(namespace, neighbor)| (namespace.as_str(), neighbor.document.title.as_str()))
+        .collect();
+    assert_eq!(
+        titles,
+        vec![
+            ("default", content_fixture[0]),
+            ("odd", content_fixture[1]),
+            ("default", content_fixture[2]),
+            ("default", content_fixture[4]),
+            ("odd", content_fixture[5]),
+            ("odd", content_fixture[3]),
+        ]
+    );
+
+    let result = namespaces
+        .search(
+            &["odd".to_owned(), "missing".to_owned()],
+            &query,
+            6,
+            &options,
+        )
+        .unwrap();
+    assert_eq!(result.len(), 3);
+    assert!(result.iter().all(|(namespace, _)| namespace == "odd"));
+}
+
+#[rstest]
+fn it_serializes_namespaces_independently(resource_fixture: Resource) {
+    let mut namespaces = engine::Namespaces::default();
+    namespaces.insert(
+        "docs",
+        engine::index(resource_fixture, &Default::default()).unwrap(),
+    );
+    engine::clear(namespaces.get_mut("tickets"));
+
+    let serialized = serde_json::to_string(namespaces.get("docs").unwrap()).unwrap();
+    let docs: engine::Index = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(engine::size(&docs), 6);
+
+    let serialized = serde_json::to_string(&namespaces).unwrap();
+    let namespaces: engine::Namespaces = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(engine::size(namespaces.get("docs").unwrap()), 6);
+    assert_eq!(engine::size(namespaces.get("tickets").unwrap()), 0);
+}
+
+#[rstest]
+fn it_reads_namespaces_on_a_small_stack(resource_fixture: Resource, question_fixture: [f32; 768]) {
+    let index = engine::index(resource_fixture, &Default::default()).unwrap();
+    let mut namespaces = engine::Namespaces::default();
+    for name in ["docs", "tickets", "chats"] {
+        namespaces.insert(name, index.clone());
+    }
+    let serialized = engine::serialize_namespaces(&namespaces).unwrap();
+
+    // A fraction of the default stack size. Unoptimized builds used to copy
+    // whole leaves of the trees on the stack while reading them, and overflowed
+    // even the default.
+    let reader = std::thread::Builder::new().stack_size(128 * 1024);
+    let sizes = reader
+        .spawn(move || {
+            let Ok(engine::Snapshot::Namespaces(namespaces)) = engine::deserialize(&serialized)
+            else {
+                panic!("expected namespaces");
+            };
+            let query = engine::Query::Embeddings(question_fixture.to_vec());
+            let names = namespaces.names();
+            let result = namespaces
+                .search(&names, &query, 3, &Default::default())
+                .unwrap();
+            (names.len(), result.len())
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+    assert_eq!(sizes, (3, 3));
+}
+
+#[rstest]
+fn it_searches_a_batch_of_queries(
+    resource_fixture: Resource,
+    question_fixture: [f32; 768],
+    embedding_fixture: [[f32; 768]; 6],
+) {
+    let index = engine::index(resource_fixture, &Default::default()).unwrap();
+    let queries = vec![
+        engine::Query::Embeddings(question_fixture.to_vec()),
+        engine::Query::Embeddings(embedding_fixture[3].to_vec()),
+    ];
+    let options = Default::default();
+
+    let result = engine::search_batch(&index, &queries, 3, &options).unwrap();
+    assert_eq!(result.len(), 2);
+    for (query, neighbors) in queries.iter().zip(result) {
+        let expected = engine::search(&index, query, 3, &options).unwrap();
+        let titles: Vec<String> = neighbors.into_iter().map(|n| n.document.title).collect();
+        let expected: Vec<String> = expected.into_iter().map(|n| n.document.title).collect();
+        assert_eq!(titles, expected);
+    }
+}
+
+#[rstest]
+fn it_builds_large_indexes_in_partitions(embedding_fixture: [[f32; 768]; 6]) {
+    let resource = Resource {
+        embeddings: (0..engine::PARALLEL_BUILD_THRESHOLD)
+            .map(|i| EmbeddedResource {
+                id: i.to_string(),
+                title: i.to_string(),
+                url: "".to_owned(),
+                embeddings: embedding_fixture[i % 6]
+                    .iter()
+                    .map(|x| x + i as f32 * 1e-4)
+                    .collect(),
+                chunks: None,
+                metadata: None,
+            })
+            .collect(),
+    };
+    let queries: Vec<engine::Query> = [7, 1000, 4000]
+        .iter()
+        .map(|i| engine::Query::Embeddings(resource.embeddings[*i].embeddings.to_owned()))
+        .collect();
+
+    #[cfg(not(feature = "threads"))]
+    let index = engine::index(resource, &Default::default()).unwrap();
+    #[cfg(feature = "threads")]
+    let index = rayon::ThreadPoolBuilder::new()
+        .num_threads(4)
+        .build()
+        .unwrap()
+        .install(|| engine::index(resource, &Default::default()).unwrap());
+    #[cfg(feature = "threads")]
+    assert_eq!(index.segments.len(), 4);
+    assert_eq!(engine::size(&index), engine::PARALLEL_BUILD_THRESHOLD);
+
+    let result = engine::search_batch(&index, &queries, 1, &Default::default()).unwrap();
+    let titles: Vec<&str> = result
+        .iter()
+        .map(|neighbors| neighbors[0].document.title.as_str())
+        .collect();
+    assert_eq!(titles, vec!["7", "1000", "4000"]);
+}
+
+#[rstest]
+fn it_builds_index_from_batches(
+    resource_fixture: Resource,
+    question_fixture: [f32; 768],
+    content_fixture: [&'static str; 6],
+) {
+    let progress = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let reported = progress.clone();
+    let mut builder =
+        engine::IndexBuilder::new().on_progress(move |pushed| reported.borrow_mut().push(pushed));
+
+    let mut batches = resource_fixture.embeddings.into_iter().peekable();
+    while batches.peek().is_some() {
+        builder.push(batches.by_ref().take(4));
+    }
+    let index = builder.finish().unwrap();
+    assert_eq!(*progress.borrow(), vec![4, 6]);
+    assert_eq!(index.segments.len(), 1);
+    assert_eq!(engine::size(&index), 6);
+
+    let query = engine::Query::Embeddings(question_fixture.to_vec());
+    let result = engine::search(&index, &query, 6, &Default::default()).unwrap();
+    let titles: Vec<String> = result.into_iter().map(|n| n.document.title).collect();
+    assert_eq!(
+        titles,
+        [0, 1, 2, 4, 5, 3].map(|i| content_fixture[i].to_owned())
+    );
+}
+
+// A k-d tree built by inserting the points one at a time, the way trees were
+// built before bulk builds: a leaf that outgrows its bucket splits at its median,
+// on the dimension of its depth.
+enum Incremental {
+    Leaf(Vec<usize>),
+    Stem(usize, f32, Box<Incremental>, Box<Incremental>),
+}
+
+impl Incremental {
+    fn insert(&mut self, points: &[Vec<f32>], point: usize, depth: usize) {
+        match self {
+            Incremental::Stem(dimension, split, left, right) => {
+                let next = if points[point][*dimension] < *split {
+                    left
+                } else {
+                    right
+                };
+                next.insert(points, point, depth + 1);
+            }
+            Incremental::Leaf(leaf) => {
+                leaf.push(point);
+                if leaf.len() <= 32 {
+                    return;
+                }
+                let dimension = depth % points[point].len();
+                let coordinate = |point: &usize| points[*point][dimension];
+                leaf.sort_by(|a, b| coordinate(a).partial_cmp(&coordinate(b)).unwrap());
+                let right = leaf.split_off(leaf.len() / 2);
+                let left = std::mem::take(leaf);
+                let split = coordinate(&right[0]);
+                *self = Incremental::Stem(
+                    dimension,
+                    split,
+                    Box::new(Incremental::Leaf(left)),
+                    Box::new(Incremental::Leaf(right)),
+                );
+            }
+        }
+    }
+
+    fn depth(&self) -> usize {
+        match self {
+            Incremental::Leaf(_) => 0,
+            Incremental::Stem(_, _, left, right) => 1 + left.depth().max(right.depth()),
+        }
+    }
+
+    // Nearest side first, like `Tree::walk`, calling "distance" once per point
+    // of the leaves reached.
+    fn walk(
+        &self,
+        query: &[f32],
+        candidates: &mut super::segment::Candidates<u64>,
+        distance: &mut impl FnMut(usize) -> f32,
+    ) {
+        match self {
+            Incremental::Leaf(leaf) => {
+                for point in leaf {
+                    candidates.push(distance(*point), *point as u64);
+                }
+            }
+            Incremental::Stem(dimension, split, left, right) => {
+                let difference = query[*dimension] - split;
+                let (near, far) = if difference < 0.0 {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+                near.walk(query, candidates, distance);
+                if difference * difference <= candidates.worst() {
+                    far.walk(query, candidates, distance);
+                }
+            }
+        }
+    }
+}
+
+#[rstest]
+fn it_builds_balanced_trees() {
+    use super::segment::{sort_by_distance, squared_euclidean, Candidates};
+
+    // Few dimensions, where k-d trees prune most of the points. Points sorted
+    // along the first axis are the worst case for incremental insertion: every
+    // split of the first axis happens at the end of the tree.
+    let mut random = Random::new(7);
+    let mut coordinates = |n: usize| -> Vec<Vec<f32>> {
+        (0..n)
+            .map(|_| (0..4).map(|_| random.next_f64() as f32).collect())
+            .collect()
+    };
+    let mut points = coordinates(4096);
+    points.sort_by(|a, b| a[0].partial_cmp(&b[0]).unwrap());
+    let queries = coordinates(64);
+
+    let chunks: Vec<((u64, u32), &[f32])> = points
+        .iter()
+        .enumerate()
+        .map(|(i, embeddings)| ((i as u64, 0), embeddings.as_slice()))
+        .collect();
+    let tree = tree_of(&chunks);
+    assert_eq!(tree.size(), 4096);
+
+    let depths = tree.leaf_depths();
+    let (min, max) = (*depths.iter().min().unwrap(), *depths.iter().max().unwrap());
+    assert!(max - min <= 1);
+    // Halving 4096 points down to leaves of 32 takes 7 levels.
+    assert!(max <= 7);
+
+    let mut incremental = Incremental::Leaf(vec![]);
+    for i in 0..points.len() {
+        incremental.insert(&points, i, 0);
+    }
+    assert!(max < incremental.depth());
+
+    // Both trees find the same neighbors as an exhaustive search, up to ties,
+    // and the balanced one computes fewer distances to find them, which is
+    // what a search costs.
+    let (mut balanced_distances, mut incremental_distances) = (0, 0);
+    for query in &queries {
+        let mut expected: Vec<(f32, u64)> = points
+            .iter()
+            .enumerate()
+            .map(|(i, embeddings)| (squared_euclidean(query, embeddings), i as u64))
+            .collect();
+        sort_by_distance(&mut expected);
+        let expected: Vec<f32> = expected[..10]
+            .iter()
+            .map(|(distance, _)| *distance)
+            .collect();
+
+        let distance = |point: usize, count: &mut usize| {
+            *count += 1;
+            squared_euclidean(query, &points[point])
+        };
+
+        let mut candidates = Candidates::new(10);
+        tree.walk(query, |leaf| {
+            for (i, _) in leaf {
+                let distance = distance(*i as usize, &mut balanced_distances);
+                candidates.push(distance, *i);
+            }
+            candidates.worst()
+        });
+        let result: Vec<f32> = candidates.points.iter().map(|(d, _)| *d).collect();
+        assert_eq!(result, expected);
+
+        let mut candidates = Candidates::new(10);
+        incremental.walk(query, &mut candidates, &mut |point| {
+            distance(point, &mut incremental_distances)
+        });
+        let result: Vec<f32> = candidates.points.iter().map(|(d, _)| *d).collect();
+        assert_eq!(result, expected);
+    }
+    assert!(balanced_distances < incremental_distances);
+
+    // Reading an index back rebuilds its tree with the same shape.
+    let resource = Resource {
+        embeddings: points
+            .iter()
+            .enumerate()
+            .map(|(i, embeddings)| EmbeddedResource {
+                id: i.to_string(),
+                title: "".to_owned(),
+                url: "".to_owned(),
+                embeddings: embeddings.to_owned(),
+                chunks: None,
+                metadata: None,
+            })
+            .collect(),
+    };
+    let index = engine::index(resource, &Default::default()).unwrap();
+    let serialized = engine::serialize_index(&index).unwrap();
+    let restored = engine::deserialize_index(&serialized).unwrap();
+    let (tree, restored) = (&index.segments[0].tree, &restored.segments[0].tree);
+    assert_eq!(restored.stems, tree.stems);
+    assert_eq!(restored.leaves, tree.leaves);
+    assert_eq!(restored.points, tree.points);
+}
+
+#[rstest]
+fn it_builds_trees_with_index_options(
+    resource_fixture: Resource,
+    question_fixture: [f32; 768],
+    content_fixture: [&'static str; 6],
+) {
+    let options = engine::IndexOptions {
+        bucket_size: 16,
+        index: engine::IndexWidth::U32,
+        ..Default::default()
+    };
+    let index = engine::index(resource_fixture, &options).unwrap();
+
+    let serialized = serde_json::to_string(&index).unwrap();
+    let index: engine::Index = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(index.options, options);
+    assert_eq!(index.segments[0].tree.options(), options);
+
+    let query = engine::Query::Embeddings(question_fixture.to_vec());
+    let result = engine::search(&index, &query, 1, &Default::default()).unwrap();
+    assert_eq!(result[0].document.title, content_fixture[0]);
+
+    let stats = engine::stats(&index).unwrap();
+    assert_eq!(stats.segments[0].bucket_fill, 6.0 / 16.0);
+}
+
+#[rstest]
+fn it_rejects_invalid_index_options(resource_fixture: Resource) {
+    let options = engine::IndexOptions {
+        bucket_size: 48,
+        ..Default::default()
+    };
+    let error = engine::index(resource_fixture, &options).unwrap_err();
+    assert!(error.to_string().contains("unsupported bucket size 48"));
+
+    let narrow = engine::IndexOptions {
+        bucket_size: 8,
+        index: engine::IndexWidth::U16,
+        ..Default::default()
+    };
+    let error = narrow.check_capacity(1_000_000).unwrap_err();
+    assert!(error.to_string().contains("u32 index width"));
+    assert!(narrow.check_capacity(100_000).is_ok());
+
+    let wide = engine::IndexOptions {
+        index: engine::IndexWidth::U32,
+        ..narrow
+    };
+    assert!(wide.check_capacity(1_000_000).is_ok());
+}
+
+#[rstest]
+fn it_reads_indexes_of_previous_formats() {
+    let query = engine::Query::Embeddings(vec![1.0, 1.0, 0.5, -1.0]);
+
+    let baseline = engine::deserialize_index(include_str!("snapshots/baseline.json")).unwrap();
+    assert_eq!(engine::size(&baseline), 3);
+    let result = engine::search(&baseline, &query, 1, &Default::default()).unwrap();
+    assert_eq!(result[0].document.id, "a");
+    let (document, embeddings) = engine::find(&baseline, "b").unwrap();
+    assert_eq!(document.title, "Title b");
+    assert_eq!(*embeddings.decode(), [vec![2.0, 0.5, 0.5, -2.0]]);
+
+    // One snapshot per format version, with a packed segment, a tombstone, a
+    // write-ahead log and metadata.
+    let packed = engine::deserialize_index(include_str!("snapshots/version-1.json")).unwrap();
+    assert_eq!(packed.options.precision, engine::Precision::F16);
+    assert_eq!(packed.segments[0].tree.size(), 1);
+    engine::verify(&packed).unwrap();
+    assert_eq!(engine::size(&packed), 2);
+    assert!(engine::find(&packed, "b").is_none());
+    assert_eq!(
+        engine::find(&packed, "a").unwrap().0.metadata.0,
+        BTreeMap::from([("timestamp".to_owned(), 1.0)])
+    );
+    assert_eq!(
+        *engine::find(&packed, "a").unwrap().1.decode(),
+        [vec![1.0, 1.0, 0.5, -1.0]]
+    );
+    assert_eq!(
+        *engine::find(&packed, "c").unwrap().1.decode(),
+        [vec![3.0, 0.33325195, 0.5, -3.0]]
+    );
+    // The log was written in f32, and is held in the precision of the index.
+    assert_eq!(
+        engine::find(&packed, "c").unwrap().1.precision(),
+        engine::Precision::F16
+    );
+    let result = engine::search(&packed, &query, 2, &Default::default()).unwrap();
+    let ids: Vec<&str> = result.iter().map(|n| n.document.id.as_str()).collect();
+    assert_eq!(ids, vec!["a", "c"]);
+    let serialized = engine::serialize_index(&packed).unwrap();
+    assert!(serialized.contains("\"3c003c003800bc00\""));
+    assert!(serialized.contains("\"420035553800c200\""));
+    let reread = engine::deserialize_index(&serialized).unwrap();
+    assert_eq!(engine::find(&reread, "c"), engine::find(&packed, "c"));
+}
+
+#[rstest]
+fn it_writes_the_format_version(resource_fixture: Resource) {
+    let index = engine::index(resource_fixture, &Default::default()).unwrap();
+    let serialized = engine::serialize_index(&index).unwrap();
+    assert!(serialized.starts_with(&format!("{{\"version\":{}", engine::FORMAT_VERSION)));
+    let index = engine::deserialize_index(&serialized).unwrap();
     assert_eq!(engine::size(&index), 6);
+
+    let serialized = engine::serialize_namespaces(&engine::Namespaces::new(index)).unwrap();
+    match engine::deserialize(&serialized).unwrap() {
+        engine::Snapshot::Namespaces(namespaces) => {
+            assert_eq!(
+                namespaces.names(),
+                vec![engine::DEFAULT_NAMESPACE.to_owned()]
+            );
+        }
+        snapshot => panic!("expected namespaces, got {:?}", snapshot),
+    }
+    assert!(engine::deserialize_index(&serialized).is_err());
+}
+
+#[rstest]
+fn it_rejects_newer_format_versions() {
+    let serialized = format!(
+        "{{\"version\":{},\"index\":{{}}}}",
+        engine::FORMAT_VERSION + 1
+    );
+    let error = engine::deserialize(&serialized).unwrap_err();
+    assert!(error.to_string().starts_with(&format!(
+        "incompatible index format version {}",
+        engine::FORMAT_VERSION + 1
+    )));
+}
+
+#[rstest]
+fn it_rejects_indexes_that_fail_their_checksum(resource_fixture: Resource) {
+    let index = engine::index(resource_fixture, &Default::default()).unwrap();
+    let serialized = engine::serialize_index(&index).unwrap();
+
+    let tampered = serialized.replacen("\"title\":\"", "\"title\":\"x", 1);
+    let error = engine::deserialize(&tampered).unwrap_err();
+    assert!(error.to_string().starts_with("checksum mismatch"));
+
+    let truncated = &serialized[..serialized.len() / 2];
+    let error = engine::deserialize(truncated).unwrap_err();
+    assert!(error.to_string().contains("truncated"));
+}
+
+#[rstest]
+fn it_checks_the_metadata_of_documents(resource_fixture: Resource) {
+    let mut resource = resource_fixture;
+    for (i, embedded) in resource.embeddings.iter_mut().enumerate() {
+        embedded.metadata = Some(BTreeMap::from([("timestamp".to_owned(), i as f64)]));
+    }
+    let index = engine::index(resource, &Default::default()).unwrap();
+    let serialized = engine::serialize_index(&index).unwrap();
+
+    let tampered = serialized.replacen("\"timestamp\":1.0", "\"timestamp\":9.0", 1);
+    assert_ne!(tampered, serialized);
+    let error = engine::deserialize(&tampered).unwrap_err();
+    assert!(error.to_string().starts_with("checksum mismatch"));
+}
+
+#[rstest]
+fn it_verifies_the_consistency_of_index(
+    resource_fixture: Resource,
+    content_fixture: [&'static str; 6],
+    embedding_fixture: [[f32; 768]; 6],
+) {
+    let mut index = engine::index(resource_fixture, &Default::default()).unwrap();
+    let target = Resource {
+        embeddings: vec![EmbeddedResource {
+            id: "1".to_owned(),
+            title: content_fixture[1].to_string(),
+            url: "".to_owned(),
+            embeddings: embedding_fixture[1].to_vec(),
+            chunks: None,
+            metadata: None,
+        }],
+    };
+    engine::remove(&mut index, &target);
+    assert_eq!(index.segments[0].tombstones.len(), 1);
+    assert!(engine::verify(&index).is_ok());
+
+    // Point the tree to a chunk of "2" that doesn't exist.
+    let segment = &mut index.segments[0];
+    let (id, _) = segment
+        .data
+        .iter()
+        .find(|(_, document)| document.id == "2")
+        .unwrap();
+    let id = *id;
+    let chunks: Vec<Vec<f32>> = segment
+        .embeddings
+        .values()
+        .map(|embeddings| embeddings.decode()[0].to_owned())
+        .collect();
+    let points: Vec<((u64, u32), &[f32])> = segment
+        .embeddings
+        .keys()
+        .zip(&chunks)
+        .map(|(item, chunk)| ((*item, (*item == id) as u32), chunk.as_slice()))
+        .collect();
+    segment.tree = tree_of(&points);
+
+    let error = engine::verify(&index).unwrap_err();
+    assert_eq!(
+        format!("{:#}", error),
+        "segment 0: the tree holds chunk 1 of document \"2\" but it has 1 embeddings"
+    );
+
+    index.segments[0].embeddings.remove(&id);
+    let error = engine::verify(&index).unwrap_err();
+    assert!(format!("{:#}", error).contains("document \"2\" has no embeddings"));
+}
+
+#[rstest]
+fn it_verifies_the_shape_of_trees() {
+    let mut random = Random::new(3);
+    let embeddings = (0..64)
+        .map(|i| EmbeddedResource {
+            id: i.to_string(),
+            title: "".to_owned(),
+            url: "".to_owned(),
+            embeddings: (0..4).map(|_| random.next_f64() as f32).collect(),
+            chunks: None,
+            metadata: None,
+        })
+        .collect();
+    let options = engine::IndexOptions {
+        bucket_size: 8,
+        ..Default::default()
+    };
+    let index = engine::index(Resource { embeddings }, &options).unwrap();
+    assert_eq!(index.segments.len(), 1);
+    assert_eq!(index.segments[0].tree.leaves.len(), 8);
+    engine::verify(&index).unwrap();
+
+    let tampered = |tamper: &dyn Fn(&mut super::tree::Tree)| -> String {
+        let mut index = index.clone();
+        tamper(&mut index.segments[0].tree);
+        format!("{:#}", engine::verify(&index).unwrap_err())
+    };
+    let root = index.segments[0].tree.root as usize;
+
+    // Every point left of the root is now past its split.
+    let error = tampered(&|tree| tree.stems[root].split = -1.0);
+    assert!(
+        error.contains("is on the wrong side of the split at -1"),
+        "{}",
+        error
+    );
+
+    let error = tampered(&|tree| tree.stems[root].right = tree.stems[root].left);
+    assert!(error.contains("of the tree is reached twice"), "{}", error);
+
+    let error = tampered(&|tree| tree.stems[root].left = 1 << 31 | 100);
+    assert_eq!(error, "segment 0: the tree points to leaf 100 of 8");
+
+    let error = tampered(&|tree| tree.stems[root].left = 100);
+    assert_eq!(error, "segment 0: the tree points to stem 100 of 7");
+
+    let error = tampered(&|tree| tree.leaves[0].1 += 1);
+    assert_eq!(
+        error,
+        "segment 0: the leaves of the tree hold 65 points but it has 64"
+    );
+
+    let error = tampered(&|tree| {
+        tree.leaves[0].1 += 1;
+        tree.leaves[7].1 -= 1;
+    });
+    assert_eq!(error, "segment 0: leaves of the tree overlap");
+
+    let error = tampered(&|tree| tree.leaves[0].1 -= 1);
+    assert_eq!(
+        error,
+        "segment 0: the leaves of the tree hold 63 points but it has 64"
+    );
+}
+
+#[rstest]
+fn it_reads_serialized_bytes(resource_fixture: Resource) {
+    let index = engine::index(resource_fixture, &Default::default()).unwrap();
+    let serialized = engine::serialize_index(&index).unwrap();
+
+    let snapshot = engine::deserialize_bytes(serialized.as_bytes()).unwrap();
+    assert_eq!(engine::size(&snapshot.into_index().unwrap()), 6);
+
+    let error = engine::deserialize_bytes(&[0xff, 0xfe]).unwrap_err();
+    assert!(error.to_string().contains("neither JSON nor compressed"));
+}
+
+#[cfg(feature = "compression")]
+#[rstest]
+fn it_compresses_serialized_indexes(resource_fixture: Resource) {
+    let index = engine::index(resource_fixture, &Default::default()).unwrap();
+    let serialized = engine::serialize_index(&index).unwrap();
+
+    let compressed = engine::compress(&serialized);
+    assert!(compressed.len() < serialized.len() / 2);
+    let snapshot = engine::deserialize_bytes(&compressed).unwrap();
+    assert_eq!(engine::size(&snapshot.into_index().unwrap()), 6);
+
+    let error = engine::deserialize_bytes(&compressed[..compressed.len() / 2]).unwrap_err();
+    assert!(error.to_string().contains("truncated"));
+}
+
+#[cfg(not(feature = "compression"))]
+#[rstest]
+fn it_requires_the_compression_feature_for_compressed_indexes() {
+    let error = engine::deserialize_bytes(b"\0voy\x01").unwrap_err();
+    assert!(error.to_string().contains("\"compression\" feature"));
+}
+
+#[rstest]
+fn it_searches_frozen_indexes(question_fixture: [f32; 768], embedding_fixture: [[f32; 768]; 6]) {
+    // Chunked documents spread around the fixtures, in a tree of several levels.
+    let resource = Resource {
+        embeddings: (0..300)
+            .map(|i| EmbeddedResource {
+                id: i.to_string(),
+                title: format!("Title {}", i),
+                url: format!("/path/{}", i),
+                embeddings: vec![],
+                chunks: Some(
+                    (0..1 + i % 3)
+                        .map(|c| {
+                            embedding_fixture[(i + c) % 6]
+                                .iter()
+                                .enumerate()
+                                .map(|(d, x)| {
+                                    let noise = (i * 7919 + c * 104_729 + d * 31) % 1009;
+                                    x + noise as f32 * 1e-4
+                                })
+                                .collect()
+                        })
+                        .collect(),
+                ),
+                metadata: Some(BTreeMap::from([("timestamp".to_owned(), i as f64)])),
+            })
+            .collect(),
+    };
+    let mut index = engine::index(resource, &Default::default()).unwrap();
+    engine::remove(
+        &mut index,
+        &Resource {
+            embeddings: vec![EmbeddedResource {
+                id: "0".to_owned(),
+                title: "Title 0".to_owned(),
+                url: "/path/0".to_owned(),
+                embeddings: vec![],
+                chunks: None,
+                metadata: None,
+            }],
+        },
+    );
+
+    let bytes = engine::freeze(&index).unwrap();
+    let frozen = engine::FrozenIndex::open(bytes.as_slice()).unwrap();
+    assert_eq!(frozen.size(), 299);
+
+    let query = engine::Query::Embeddings(question_fixture.to_vec());
+    for aggregation in [
+        engine::Aggregation::Max,
+        engine::Aggregation::Mean,
+        engine::Aggregation::Sum,
+    ] {
+        let mut options = engine::SearchOptions {
+            aggregation,
+            top_chunks: 2,
+            ..Default::default()
+        };
+        let expected = engine::search(&index, &query, 10, &options).unwrap();
+        assert_eq!(frozen.search(&query, 10, &options).unwrap(), expected);
+
+        options.boosts = vec![engine::Boost::Decay {
+            field: "timestamp".to_owned(),
+            now: 300.0,
+            half_life: 50.0,
+        }];
+        let expected = engine::search(&index, &query, 10, &options).unwrap();
+        assert_eq!(frozen.search(&query, 10, &options).unwrap(), expected);
+
+        options.exclude_ids = expected
+            .iter()
+            .map(|neighbor| neighbor.document.id.to_owned())
+            .collect();
+        let expected = engine::search(&index, &query, 10, &options).unwrap();
+        assert_eq!(expected.len(), 10);
+        assert_eq!(frozen.search(&query, 10, &options).unwrap(), expected);
+    }
+
+    let error = engine::FrozenIndex::open(&bytes[..bytes.len() - 1]).unwrap_err();
+    assert!(error.to_string().contains("truncated"));
+    let error = engine::deserialize_bytes(&bytes).unwrap_err();
+    assert!(error.to_string().contains("FrozenIndex"));
+
+    let empty = engine::freeze(&engine::Index::default()).unwrap();
+    let empty = engine::FrozenIndex::open(empty).unwrap();
+    assert!(empty
+        .search(&query, 3, &Default::default())
+        .unwrap()
+        .is_empty());
+}
+
+#[rstest]
+fn it_rejects_corrupted_frozen_indexes(resource_fixture: Resource, question_fixture: [f32; 768]) {
+    let index = engine::index(resource_fixture, &Default::default()).unwrap();
+    let bytes = engine::freeze(&index).unwrap();
+    let query = engine::Query::Embeddings(question_fixture.to_vec());
+    let options = engine::SearchOptions {
+        exclude_ids: std::collections::HashSet::from(["0".to_owned()]),
+        aggregation: engine::Aggregation::Mean,
+        ..Default::default()
+    };
+
+    // Corrupted bytes are rejected when opened, or searched without a panic.
+    let mut corrupted = bytes.clone();
+    for i in 0..bytes.len() {
+        for flip in [0x01, 0x80, 0xff] {
+            corrupted[i] = bytes[i] ^ flip;
+            if let Ok(frozen) = engine::FrozenIndex::open(corrupted.as_slice()) {
+                let _ = frozen.search(&query, 4, &options);
+            }
+        }
+        corrupted[i] = bytes[i];
+    }
+
+    // Header fields at their largest overflow the offsets on 32 bit targets.
+    for field in 0..7 {
+        let mut corrupted = bytes.clone();
+        corrupted[8 + field * 4..12 + field * 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(engine::FrozenIndex::open(corrupted).is_err());
+    }
+    // Stems that point back up the tree, or share a child, are rejected.
+    let mut random = Random::new(5);
+    let embeddings = (0..64)
+        .map(|i| EmbeddedResource {
+            id: i.to_string(),
+            title: "".to_owned(),
+            url: "".to_owned(),
+            embeddings: (0..4).map(|_| random.next_f64() as f32).collect(),
+            chunks: None,
+            metadata: None,
+        })
+        .collect();
+    let options = engine::IndexOptions {
+        bucket_size: 8,
+        ..Default::default()
+    };
+    let index = engine::index(Resource { embeddings }, &options).unwrap();
+    let bytes = engine::freeze(&index).unwrap();
+    engine::FrozenIndex::open(bytes.as_slice()).unwrap();
+    // The root is the first stem, right after the 36 bytes of the header.
+    let left = bytes[44..48].to_vec();
+    let mut shared = bytes.clone();
+    shared[48..52].copy_from_slice(&left);
+    let error = engine::FrozenIndex::open(shared).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "the frozen index is corrupted: a node has several parents"
+    );
+    let mut cycle = bytes;
+    cycle[44..48].copy_from_slice(&0u32.to_le_bytes());
+    let error = engine::FrozenIndex::open(cycle).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "the frozen index is corrupted: a stem is out of bounds"
+    );
+
+    let normalized = engine::IndexOptions {
+        normalize: true,
+        ..Default::default()
+    };
+    let index = engine::index(Resource { embeddings: vec![] }, &normalized).unwrap();
+    let error = engine::freeze(&index).unwrap_err();
+    assert!(error.to_string().contains("normalize"));
+}
+
+#[rstest]
+fn it_bumps_the_version_on_changes(resource_fixture: Resource) {
+    let mut resources = resource_fixture.embeddings;
+    let extra = Resource {
+        embeddings: resources.split_off(4),
+    };
+    let mut index = engine::index(
+        Resource {
+            embeddings: resources,
+        },
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(index.changes.version, 0);
+
+    engine::add(&mut index, &extra).unwrap();
+    assert_eq!(index.changes.version, 1);
+    engine::remove(&mut index, &extra);
+    assert_eq!(index.changes.version, 2);
+    engine::remove(&mut index, &extra);
+    assert_eq!(index.changes.version, 2);
+
+    engine::flush(&mut index).unwrap();
+    engine::compact(&mut index).unwrap();
+    assert_eq!(index.changes.version, 2);
+
+    engine::clear(&mut index);
+    assert_eq!(index.changes.version, 3);
+
+    // Versions past what a JS number holds exactly are rejected on load.
+    let serialized = engine::serialize_index(&index).unwrap();
+    assert!(serialized.contains("\"changes\":{\"version\":3,"));
+    let beyond = serialized.replace(
+        "\"changes\":{\"version\":3,",
+        &format!("\"changes\":{{\"version\":{},", engine::MAX_VERSION + 1),
+    );
+    let error = engine::deserialize_index(&beyond).unwrap_err();
+    assert!(error.to_string().contains("exceeds the largest supported"));
+}
+
+#[rstest]
+fn it_exports_and_applies_changes(resource_fixture: Resource) {
+    let mut resources = resource_fixture.embeddings;
+    let added = resources.pop().unwrap();
+    let mut server = engine::index(
+        Resource {
+            embeddings: resources,
+        },
+        &Default::default(),
+    )
+    .unwrap();
+    let mut client = server.clone();
+
+    let mut updated = server_resource(&server, "1");
+    updated.embeddings[0] += 1.0;
+    let removed = server_resource(&server, "2");
+    let temporary = EmbeddedResource {
+        id: "temporary".to_owned(),
+        ..updated.clone()
+    };
+    engine::add(
+        &mut server,
+        &Resource {
+            embeddings: vec![added, temporary.clone()],
+        },
+    )
+    .unwrap();
+    engine::add(
+        &mut server,
+        &Resource {
+            embeddings: vec![updated.clone()],
+        },
+    )
+    .unwrap();
+    engine::remove(
+        &mut server,
+        &Resource {
+            embeddings: vec![removed, temporary],
+        },
+    );
+
+    let delta = engine::export_changes_since(&server, 0);
+    let ids = |entries: &[(engine::Document, Vec<Vec<f32>>)]| -> Vec<String> {
+        entries
+            .iter()
+            .map(|(document, _)| document.id.to_owned())
+            .collect()
+    };
+    assert_eq!(delta.version, 3);
+    assert!(!delta.cleared);
+    assert_eq!(ids(&delta.added), vec!["5"]);
+    assert_eq!(ids(&delta.updated), vec!["1"]);
+    let removed: Vec<&str> = delta.removed.iter().map(|d| d.id.as_str()).collect();
+    assert_eq!(removed, vec!["2"]);
+
+    engine::apply_changes(&mut client, &delta).unwrap();
+    assert_eq!(engine::size(&client), engine::size(&server));
+    assert_eq!(
+        *engine::find(&client, "1").unwrap().1.decode(),
+        [updated.embeddings.clone()]
+    );
+    assert!(engine::find(&client, "2").is_none());
+
+    let unchanged = engine::export_changes_since(&server, delta.version);
+    assert!(unchanged.added.is_empty() && unchanged.updated.is_empty());
+    assert!(unchanged.removed.is_empty());
+    engine::clear(&mut server);
+    engine::add(
+        &mut server,
+        &Resource {
+            embeddings: vec![updated],
+        },
+    )
+    .unwrap();
+    let delta = engine::export_changes_since(&server, delta.version);
+    assert!(delta.cleared);
+    assert_eq!(ids(&delta.added), vec!["1"]);
+
+    engine::apply_changes(&mut client, &delta).unwrap();
+    assert_eq!(engine::size(&client), 1);
+}
+
+#[rstest]
+fn it_truncates_the_change_history_on_compact(resource_fixture: Resource) {
+    let mut server = engine::index(resource_fixture, &Default::default()).unwrap();
+    let mut client = server.clone();
+    let removed = server_resource(&server, "2");
+    engine::remove(
+        &mut server,
+        &Resource {
+            embeddings: vec![removed],
+        },
+    );
+    let version = server.changes.version;
+
+    engine::compact(&mut server).unwrap();
+    assert!(server
+        .changes
+        .changes
+        .values()
+        .all(|change| change.removed.is_none()));
+
+    // The removal is gone, so the changes since before it are the whole index.
+    let delta = engine::export_changes_since(&server, 0);
+    assert!(delta.cleared);
+    assert_eq!(delta.added.len(), engine::size(&server));
+    engine::apply_changes(&mut client, &delta).unwrap();
+    assert_eq!(engine::size(&client), engine::size(&server));
+    assert!(engine::find(&client, "2").is_none());
+
+    let unchanged = engine::export_changes_since(&server, version);
+    assert!(!unchanged.cleared && unchanged.added.is_empty());
+}
+
+#[rstest]
+fn it_validates_applied_changes(resource_fixture: Resource) {
+    let mut index = engine::index(resource_fixture, &Default::default()).unwrap();
+    let (document, chunks) = engine::find(&index, "1").unwrap();
+    let mut delta = engine::Delta {
+        version: 1,
+        removed: vec![document.to_owned()],
+        updated: vec![(document.to_owned(), vec![chunks.decode()[0][..3].to_vec()])],
+        ..Default::default()
+    };
+
+    let error = engine::apply_changes(&mut index, &delta).unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("expected 768 dimensions, found 3"));
+    assert_eq!(engine::size(&index), 6);
+    assert_eq!(index.changes.version, 0);
+
+    delta.updated[0].1 = vec![vec![f32::NAN; 768]];
+    assert!(engine::apply_changes(&mut index, &delta).is_err());
+}
+
+#[rstest]
+fn it_merges_indexes(resource_fixture: Resource, question_fixture: [f32; 768]) {
+    let mut resources = resource_fixture.embeddings.clone();
+    let second = Resource {
+        embeddings: resources.split_off(3),
+    };
+    let first = Resource {
+        embeddings: resources,
+    };
+    let a = engine::index(first, &Default::default()).unwrap();
+    let resources = second.embeddings.clone();
+    let b = engine::index(second, &Default::default()).unwrap();
+    let union = engine::index(resource_fixture, &Default::default()).unwrap();
+
+    let merged = engine::merge(&a, &b, Default::default()).unwrap();
+    let query = engine::Query::Embeddings(question_fixture.to_vec());
+    let ids = |index: &engine::Index| -> Vec<String> {
+        engine::search(index, &query, 6, &Default::default())
+            .unwrap()
+            .into_iter()
+            .map(|neighbor| neighbor.document.id)
+            .collect()
+    };
+    assert_eq!(engine::size(&merged), engine::size(&union));
+    assert_eq!(ids(&merged), ids(&union));
+    engine::verify(&merged).unwrap();
+
+    let mut changed = server_resource(&a, "1");
+    changed.embeddings[0] += 1.0;
+    let conflicting = engine::index(
+        Resource {
+            embeddings: vec![changed.clone()],
+        },
+        &Default::default(),
+    )
+    .unwrap();
+    assert!(engine::merge(&a, &conflicting, engine::MergeConflict::Fail).is_err());
+
+    let kept = engine::merge(&a, &conflicting, engine::MergeConflict::Keep).unwrap();
+    assert_eq!(engine::size(&kept), 3);
+    assert_eq!(
+        engine::find(&kept, "1").unwrap().1,
+        engine::find(&a, "1").unwrap().1
+    );
+
+    let replaced = engine::merge(&a, &conflicting, engine::MergeConflict::Replace).unwrap();
+    assert_eq!(engine::size(&replaced), 3);
+    assert_eq!(
+        *engine::find(&replaced, "1").unwrap().1.decode(),
+        [changed.embeddings]
+    );
+
+    let shorter = engine::index(
+        Resource {
+            embeddings: vec![EmbeddedResource {
+                id: "short".to_owned(),
+                title: "".to_owned(),
+                url: "".to_owned(),
+                embeddings: vec![0.5; 384],
+                chunks: None,
+                metadata: None,
+            }],
+        },
+        &Default::default(),
+    )
+    .unwrap();
+    assert!(engine::merge(&a, &shorter, Default::default()).is_err());
+
+    // Embeddings stored normalized or rounded can't be mixed with the others.
+    for options in [
+        engine::IndexOptions {
+            normalize: true,
+            ..Default::default()
+        },
+        engine::IndexOptions {
+            precision: engine::Precision::F16,
+            ..Default::default()
+        },
+    ] {
+        let other = engine::index(
+            Resource {
+                embeddings: resources.clone(),
+            },
+            &options,
+        )
+        .unwrap();
+        assert!(engine::merge(&a, &other, Default::default()).is_err());
+    }
+}
+
+#[rstest]
+fn it_boosts_scores_with_metadata(resource_fixture: Resource, question_fixture: [f32; 768]) {
+    let mut resource = resource_fixture;
+    for (i, embedded) in resource.embeddings.iter_mut().enumerate() {
+        embedded.metadata = Some(BTreeMap::from([
+            ("timestamp".to_owned(), i as f64 * 10.0),
+            ("weight".to_owned(), 1.0 + i as f64),
+        ]));
+    }
+    let index = engine::index(resource, &Default::default()).unwrap();
+    let query = engine::Query::Embeddings(question_fixture.to_vec());
+    let search = |boosts: Vec<engine::Boost>| -> Vec<(String, f32)> {
+        let options = engine::SearchOptions {
+            boosts,
+            ..Default::default()
+        };
+        engine::search(&index, &query, 6, &options)
+            .unwrap()
+            .into_iter()
+            .map(|neighbor| (neighbor.document.id, neighbor.score))
+            .collect()
+    };
+    let scores: BTreeMap<String, f32> = search(vec![]).into_iter().collect();
+    let boosted = |boosts: Vec<engine::Boost>, boost: &dyn Fn(usize) -> f32| {
+        let mut expected: Vec<(String, f32)> = (0..6)
+            .map(|i| (i.to_string(), boost(i) * scores[&i.to_string()]))
+            .collect();
+        expected.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        let result = search(boosts);
+        assert_eq!(result.len(), expected.len());
+        for ((id, score), (expected_id, expected_score)) in result.iter().zip(&expected) {
+            assert_eq!(id, expected_id);
+            assert!((score - expected_score).abs() < 1e-6);
+        }
+    };
+
+    boosted(
+        vec![engine::Boost::Decay {
+            field: "timestamp".to_owned(),
+            now: 50.0,
+            half_life: 10.0,
+        }],
+        &|i| 0.5f32.powi(5 - i as i32),
+    );
+    boosted(
+        vec![engine::Boost::Weight {
+            field: "weight".to_owned(),
+        }],
+        &|i| 1.0 + i as f32,
+    );
+
+    // Linear boosts add to the score, missing fields count as 0.
+    let result = search(vec![engine::Boost::Linear {
+        weights: BTreeMap::from([("weight".to_owned(), 0.5), ("missing".to_owned(), 2.0)]),
+    }]);
+    assert_eq!(result[0].0, "5");
+    assert!((result[0].1 - (scores["5"] + 3.0)).abs() < 1e-6);
+
+    // Metadata isn't part of the id, so adding a document again updates it.
+    let mut index = index;
+    let mut updated = server_resource(&index, "0");
+    updated.metadata = Some(BTreeMap::from([("weight".to_owned(), 100.0)]));
+    engine::add(
+        &mut index,
+        &Resource {
+            embeddings: vec![updated],
+        },
+    )
+    .unwrap();
+    assert_eq!(engine::size(&index), 6);
+    assert_eq!(
+        engine::find(&index, "0").unwrap().0.metadata.get("weight"),
+        Some(100.0)
+    );
+
+    // Half lives that would turn scores into NaN or infinity are rejected.
+    for half_life in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+        assert!(engine::Boost::decay("timestamp".to_owned(), 50.0, half_life).is_err());
+    }
+    assert_eq!(
+        engine::Boost::decay("timestamp".to_owned(), 50.0, 10.0).unwrap(),
+        engine::Boost::Decay {
+            field: "timestamp".to_owned(),
+            now: 50.0,
+            half_life: 10.0,
+        }
+    );
+}
+
+#[rstest]
+fn it_walks_segments_past_removed_and_filtered_resources(
+    question_fixture: [f32; 768],
+    embedding_fixture: [[f32; 768]; 6],
+) {
+    use super::engine::entries;
+    use super::segment::point;
+
+    // Chunked documents in a tree of several levels, half of them removed.
+    let embedded: Vec<EmbeddedResource> = (0..400)
+        .map(|i| EmbeddedResource {
+            id: i.to_string(),
+            title: "".to_owned(),
+            url: "".to_owned(),
+            embeddings: vec![],
+            chunks: Some(
+                (0..1 + i % 3)
+                    .map(|c| {
+                        embedding_fixture[(i + c) % 6]
+                            .iter()
+                            .enumerate()
+                            .map(|(d, x)| {
+                                x + ((i * 7919 + c * 104_729 + d * 31) % 1009) as f32 * 1e-4
+                            })
+                            .collect()
+                    })
+                    .collect(),
+            ),
+            metadata: None,
+        })
+        .collect();
+    let mut index = engine::index(
+        Resource {
+            embeddings: embedded.clone(),
+        },
+        &engine::IndexOptions {
+            bucket_size: 8,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    engine::remove(
+        &mut index,
+        &Resource {
+            embeddings: embedded.iter().step_by(2).cloned().collect(),
+        },
+    );
+    assert_eq!(index.segments.len(), 1);
+    assert!(index.wal.is_empty());
+
+    let query = point(&question_fixture);
+    let exhaustive = |options: &engine::SearchOptions| -> Vec<u64> {
+        let mut neighbors: Vec<(f32, u64)> = entries(&index)
+            .filter(|(_, document, _)| options.allows(&document.id))
+            .map(|(id, _, embeddings)| (embeddings.closest(&query).0, id))
+            .collect();
+        neighbors.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        neighbors.into_iter().take(10).map(|(_, id)| id).collect()
+    };
+    let walked = |options: &engine::SearchOptions| -> Vec<u64> {
+        index.segments[0]
+            .search(&query, 10, options)
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect()
+    };
+
+    let mut options = engine::SearchOptions::default();
+    assert_eq!(walked(&options), exhaustive(&options));
+    options.exclude_ids = (0..400).step_by(3).map(|i| i.to_string()).collect();
+    assert_eq!(walked(&options), exhaustive(&options));
+    options.allow_ids = Some((0..400).step_by(7).map(|i| i.to_string()).collect());
+    assert_eq!(walked(&options), exhaustive(&options));
+    assert_eq!(walked(&options).len(), 10);
+}
+
+#[rstest]
+fn it_filters_search_results_by_id(resource_fixture: Resource, question_fixture: [f32; 768]) {
+    let mut resources = resource_fixture.embeddings;
+    let wal = Resource {
+        embeddings: resources.split_off(4),
+    };
+    let mut index = engine::index(
+        Resource {
+            embeddings: resources,
+        },
+        &Default::default(),
+    )
+    .unwrap();
+    engine::add(&mut index, &wal).unwrap();
+    assert_eq!(index.wal.len(), 2);
+
+    let query = engine::Query::Embeddings(question_fixture.to_vec());
+    let search = |options: &engine::SearchOptions| -> Vec<String> {
+        engine::search(&index, &query, 3, options)
+            .unwrap()
+            .into_iter()
+            .map(|neighbor| neighbor.document.id)
+            .collect()
+    };
+    let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect();
+    assert_eq!(search(&Default::default()), vec!["0", "1", "2"]);
+
+    // Excluded resources leave room for the next ones, k is still filled.
+    let options = engine::SearchOptions {
+        exclude_ids: ids(&["0", "2"]),
+        ..Default::default()
+    };
+    assert_eq!(search(&options), vec!["1", "4", "5"]);
+
+    let options = engine::SearchOptions {
+        allow_ids: Some(ids(&["3", "5", "missing"])),
+        ..Default::default()
+    };
+    assert_eq!(search(&options), vec!["5", "3"]);
+
+    let options = engine::SearchOptions {
+        exclude_ids: ids(&["5"]),
+        allow_ids: Some(ids(&["3", "5"])),
+        ..Default::default()
+    };
+    assert_eq!(search(&options), vec!["3"]);
+}
+
+#[rstest]
+fn it_finds_and_handles_duplicates(resource_fixture: Resource) {
+    let copy = |id: &str, of: &str, index: &engine::Index| {
+        let mut resource = server_resource(index, of);
+        resource.id = id.to_owned();
+        resource.embeddings[0] += 1e-3;
+        resource.metadata = Some(BTreeMap::from([("copies".to_owned(), 1.0)]));
+        resource
+    };
+    let mut index = engine::index(resource_fixture, &Default::default()).unwrap();
+    let copies = Resource {
+        embeddings: vec![copy("0 copy", "0", &index)],
+    };
+    engine::add(&mut index, &copies).unwrap();
+
+    let groups = |index: &engine::Index, threshold: f32| -> Vec<Vec<String>> {
+        engine::find_duplicates(index, threshold)
+            .into_iter()
+            .map(|group| group.into_iter().map(|d| d.id.to_owned()).collect())
+            .collect()
+    };
+    assert_eq!(groups(&index, 0.01), vec![vec!["0", "0 copy"]]);
+    assert!(groups(&index, 0.0).is_empty());
+    engine::flush(&mut index).unwrap();
+    assert_eq!(groups(&index, 0.01), vec![vec!["0", "0 copy"]]);
+
+    engine::remove(&mut index, &copies);
+    index.options.duplicates = engine::DuplicatePolicy::Reject;
+    index.options.duplicate_threshold = 0.01;
+    let version = index.changes.version;
+    let copies = Resource {
+        embeddings: vec![copy("1 copy", "1", &index), copy("0 copy", "0", &index)],
+    };
+    let error = engine::add(&mut index, &copies).unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("\"1 copy\" is a duplicate of \"1\""));
+    assert_eq!(engine::size(&index), 6);
+    assert_eq!(index.changes.version, version);
+
+    // Updating a document doesn't make it a duplicate of its previous version.
+    let update = Resource {
+        embeddings: vec![copy("0", "0", &index)],
+    };
+    engine::add(&mut index, &update).unwrap();
+    assert_eq!(engine::size(&index), 6);
+
+    index.options.duplicates = engine::DuplicatePolicy::Merge;
+    // Duplicates of a duplicate are merged into the same original.
+    let mut twice = copy("2 copy again", "2", &index);
+    twice.embeddings[0] += 1e-3;
+    twice.metadata = Some(BTreeMap::from([("copies".to_owned(), 2.0)]));
+    let mut new = copy("new", "3", &index);
+    new.embeddings[0] += 1.0;
+    let copies = Resource {
+        embeddings: vec![copy("2 copy", "2", &index), twice, new],
+    };
+    engine::add(&mut index, &copies).unwrap();
+    assert_eq!(engine::size(&index), 7);
+    assert!(engine::find(&index, "2 copy").is_none());
+    assert_eq!(
+        engine::find(&index, "2").unwrap().0.metadata.get("copies"),
+        Some(2.0)
+    );
+    assert!(engine::find(&index, "new").is_some());
+}
+
+#[rstest]
+fn it_clusters_documents(embedded_fixture: Embedded) {
+    // Three groups of five documents around distant centers.
+    let resources: Vec<EmbeddedResource> = (0..15)
+        .map(|i| {
+            let mut embeddings = vec![0.0; 8];
+            embeddings[i % 3] = 10.0;
+            embeddings[3 + i % 5] = (1 + i % 4) as f32 * 0.1;
+            embedded_fixture(&format!("{}-{}", i % 3, i), embeddings)
+        })
+        .collect();
+    let index = engine::index(
+        Resource {
+            embeddings: resources.clone(),
+        },
+        &Default::default(),
+    )
+    .unwrap();
+
+    let clusters = engine::cluster(&index, 3, 10, 42).unwrap();
+    assert_eq!(clusters.len(), 3);
+    for cluster in &clusters {
+        assert_eq!(cluster.members.len(), 5);
+        let group = &cluster.members[0].id[..1];
+        assert!(cluster.members.iter().all(|d| d.id.starts_with(group)));
+        assert!(cluster.members.contains(&cluster.nearest.unwrap()));
+        assert_eq!(cluster.centroid[group.parse::<usize>().unwrap()], 10.0);
+    }
+
+    // The same seed gives the same clusters, whatever the order of the documents.
+    let mut reversed = engine::Index::default();
+    engine::add(
+        &mut reversed,
+        &Resource {
+            embeddings: resources.into_iter().rev().collect(),
+        },
+    )
+    .unwrap();
+    assert_eq!(engine::cluster(&reversed, 3, 10, 42).unwrap(), clusters);
+
+    assert_eq!(engine::cluster(&index, 20, 10, 42).unwrap().len(), 15);
+    assert!(engine::cluster(&index, 0, 10, 42).is_err());
+    assert!(engine::cluster(&engine::Index::default(), 3, 10, 42)
+        .unwrap()
+        .is_empty());
+}
+
+#[rstest]
+fn it_builds_the_knn_graph(embedding_fixture: [[f32; 768]; 6], embedded_fixture: Embedded) {
+    let resources: Vec<EmbeddedResource> = (0..40)
+        .map(|i| {
+            let mut resource = embedded_fixture(&format!("{:02}", i), vec![]);
+            resource.chunks = Some(
+                (0..1 + i % 3)
+                    .map(|c| {
+                        embedding_fixture[(i + c) % 6]
+                            .iter()
+                            .enumerate()
+                            .map(|(d, x)| {
+                                x + ((i * 7919 + c * 104_729 + d * 31) % 1009) as f32 * 1e-4
+                            })
+                            .collect()
+                    })
+                    .collect(),
+            );
+            resource
+        })
+        .collect();
+    let (built, added) = resources.split_at(30);
+    let mut index = engine::index(
+        Resource {
+            embeddings: built.to_vec(),
+        },
+        &Default::default(),
+    )
+    .unwrap();
+    engine::add(
+        &mut index,
+        &Resource {
+            embeddings: added.to_vec(),
+        },
+    )
+    .unwrap();
+
+    // Every pair of documents, by the distance between their closest chunks.
+    let chunks = |resource: &EmbeddedResource| resource.chunks.to_owned().unwrap();
+    let distance = |a: &EmbeddedResource, b: &EmbeddedResource| -> f32 {
+        let mut closest = f32::INFINITY;
+        for x in chunks(a) {
+            for y in chunks(b) {
+                let d: f32 = x.iter().zip(&y).map(|(x, y)| (x - y) * (x - y)).sum();
+                closest = closest.min(d.sqrt());
+            }
+        }
+        closest
+    };
+    let expected = |k: usize, threshold: f32| -> Vec<Vec<String>> {
+        resources
+            .iter()
+            .map(|a| {
+                let mut neighbors: Vec<(f32, &str)> = resources
+                    .iter()
+                    .filter(|b| b.id != a.id)
+                    .map(|b| (distance(a, b), b.id.as_str()))
+                    .filter(|(distance, _)| *distance < threshold)
+                    .collect();
+                neighbors.sort_by(|x, y| x.0.total_cmp(&y.0));
+                neighbors
+                    .into_iter()
+                    .take(k)
+                    .map(|(_, id)| id.to_owned())
+                    .collect()
+            })
+            .collect()
+    };
+    let graph = |k: usize, threshold: Option<f32>| -> Vec<Vec<String>> {
+        engine::knn_graph(&index, k, threshold)
+            .into_iter()
+            .map(|node| {
+                node.neighbors
+                    .iter()
+                    .map(|(document, _)| document.id.to_owned())
+                    .collect()
+            })
+            .collect()
+    };
+
+    let nodes = engine::knn_graph(&index, 5, None);
+    assert_eq!(nodes.len(), 40);
+    assert_eq!(nodes[0].document.id, "00");
+    let (neighbor, d) = nodes[0].neighbors[0];
+    let other = resources.iter().find(|r| r.id == neighbor.id).unwrap();
+    assert!((d - distance(&resources[0], other)).abs() < 1e-3);
+    assert_eq!(graph(5, None), expected(5, f32::INFINITY));
+
+    let threshold = nodes[0].neighbors[2].1 + 1e-3;
+    assert_eq!(graph(5, Some(threshold)), expected(5, threshold));
+}
+
+#[rstest]
+fn it_projects_documents(embedded_fixture: Embedded) {
+    // Points spread along (1, 1, 0, 0), with a little noise on the other axes.
+    let resources: Vec<EmbeddedResource> = (0..20)
+        .map(|i| {
+            let t = i as f32 - 9.5;
+            let embeddings = vec![t, t, (i % 3) as f32 * 0.1, (i % 2) as f32 * 0.1];
+            embedded_fixture(&format!("{:02}", i), embeddings)
+        })
+        .collect();
+    let index = engine::index(
+        Resource {
+            embeddings: resources.clone(),
+        },
+        &Default::default(),
+    )
+    .unwrap();
+
+    let (projection, coordinates) =
+        engine::project(&index, 2, engine::ProjectionMethod::Pca).unwrap();
+    assert_eq!(projection.dims(), 2);
+    let axis = &projection.components[0];
+    let half = std::f32::consts::FRAC_1_SQRT_2;
+    assert!((axis[0] - half).abs() < 1e-3 && (axis[1] - half).abs() < 1e-3);
+    let cross: f32 = axis
+        .iter()
+        .zip(&projection.components[1])
+        .map(|(a, b)| a * b)
+        .sum();
+    assert!(cross.abs() < 1e-4);
+
+    assert_eq!(coordinates.len(), 20);
+    for ((document, point), resource) in coordinates.iter().zip(&resources) {
+        assert_eq!(document.id, resource.id);
+        assert_eq!(point, &projection.apply(&resource.embeddings));
+    }
+    // The first document is the furthest along the negative side of the axis.
+    assert!((coordinates[0].1[0] + 9.5 * 2f32.sqrt()).abs() < 1e-2);
+
+    let random = |seed| engine::project(&index, 3, engine::ProjectionMethod::Random { seed });
+    let (a, a_coordinates) = random(7).unwrap();
+    let (b, _) = random(7).unwrap();
+    let (c, _) = random(8).unwrap();
+    assert_eq!(a, b);
+    assert_ne!(a, c);
+    assert_eq!(a_coordinates[3].1, a.apply(&resources[3].embeddings));
+
+    assert!(engine::project(&index, 0, engine::ProjectionMethod::Pca).is_err());
+    assert!(engine::project(&index, 5, engine::ProjectionMethod::Pca).is_err());
+}
+
+#[rstest]
+fn it_finds_principal_components_of_few_points() {
+    // Fewer points than dimensions go through their Gram matrix. Repeating them
+    // keeps the covariance but goes through the covariance matrix instead.
+    let mut points = vec![vec![0.0f32; 8]; 3];
+    points[0][0] = 3.0;
+    points[1][0] = -3.0;
+    points[2][1] = 1.0;
+    let repeated: Vec<Vec<f32>> = points.iter().cycle().take(9).cloned().collect();
+
+    let few = engine::Projection::pca(&points, 4);
+    let many = engine::Projection::pca(&repeated, 4);
+    assert_eq!(few.mean, many.mean);
+    for projection in [&few, &many] {
+        assert_eq!(projection.dims(), 4);
+        let axis = |i: usize| {
+            let mut axis = vec![0.0; 8];
+            axis[i] = 1.0;
+            axis
+        };
+        for (component, expected) in projection.components[..2].iter().zip([axis(0), axis(1)]) {
+            let error: f32 = component
+                .iter()
+                .zip(&expected)
+                .map(|(a, b)| (a - b).abs())
+                .sum();
+            assert!(error < 1e-4, "{:?}", component);
+        }
+        // The rest are orthonormal directions without variance.
+        for (i, a) in projection.components.iter().enumerate() {
+            for (j, b) in projection.components.iter().enumerate() {
+                let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((dot - expected).abs() < 1e-4);
+            }
+        }
+    }
+}
+
+#[rstest]
+fn it_reduces_embeddings(embedded_fixture: Embedded) {
+    // Points on a plane of an 8 dimensional space, with more spread along its
+    // first axis.
+    let resources: Vec<EmbeddedResource> = (0..30)
+        .map(|i| {
+            let (u, v) = ((i % 6) as f32 * 2.0, (i / 6) as f32 * 0.5);
+            let embeddings = vec![u, v + 1.0, u + v, 1.0, u - v, 0.0, v, 2.0 * u];
+            embedded_fixture(&format!("{:02}", i), embeddings)
+        })
+        .collect();
+    let resource = || Resource {
+        embeddings: resources.clone(),
+    };
+    let reduced = |dims, method| engine::IndexOptions {
+        reduction: Some(engine::Reduction { dims, method }),
+        ..Default::default()
+    };
+    let nearest = |index: &engine::Index, embeddings: &[f32]| {
+        let query = engine::Query::Embeddings(embeddings.to_vec());
+        let result = engine::search(index, &query, 1, &Default::default()).unwrap();
+        result[0].document.id.to_owned()
+    };
+
+    let truncated =
+        engine::index(resource(), &reduced(3, engine::ReductionMethod::Truncate)).unwrap();
+    let chunks = engine::find(&truncated, "07").unwrap().1.decode();
+    let (u, v) = (2.0f32, 0.5f32);
+    let norm = (u * u + (v + 1.0) * (v + 1.0) + (u + v) * (u + v)).sqrt();
+    assert_eq!(chunks[0], vec![u / norm, (v + 1.0) / norm, (u + v) / norm]);
+    assert!(truncated.projection.is_none());
+    // Queries are truncated and renormalized too, so a scaled copy of the
+    // embeddings finds the same resource.
+    let scaled: Vec<f32> = resources[7].embeddings.iter().map(|x| x * 3.0).collect();
+    assert_eq!(nearest(&truncated, &scaled), "07");
+
+    let pca = engine::index(resource(), &reduced(2, engine::ReductionMethod::Pca)).unwrap();
+    let projection = pca.projection.as_deref().unwrap();
+    assert_eq!(projection.dims(), 2);
+    for resource in &resources {
+        let chunks = engine::find(&pca, &resource.id).unwrap().1.decode();
+        assert_eq!(chunks[0], projection.apply(&resource.embeddings));
+        // The plane is kept whole, so every resource is still its own nearest.
+        assert_eq!(nearest(&pca, &resource.embeddings), resource.id);
+    }
+
+    let serialized = engine::serialize_index(&pca).unwrap();
+    let deserialized = engine::deserialize_index(&serialized).unwrap();
+    assert_eq!(deserialized.options, pca.options);
+    assert_eq!(deserialized.projection, pca.projection);
+    assert_eq!(nearest(&deserialized, &resources[12].embeddings), "12");
+
+    // Projections of a reduced index are in its reduced space, where queries
+    // are brought by reducing them first.
+    let (plot, coordinates) = engine::project(&pca, 2, engine::ProjectionMethod::Pca).unwrap();
+    let query = engine::reduce(&pca.options, Some(projection), &resources[0].embeddings);
+    assert_eq!(coordinates[0].1, plot.apply(&query));
+
+    // An index built empty learns the components from its first add, which
+    // needs at least as many embeddings as dimensions.
+    let mut added = engine::index(
+        Resource { embeddings: vec![] },
+        &reduced(2, engine::ReductionMethod::Pca),
+    )
+    .unwrap();
+    let first = Resource {
+        embeddings: resources[..1].to_vec(),
+    };
+    let error = engine::add(&mut added, &first).unwrap_err();
+    assert!(error.to_string().contains("at least 2 embeddings"));
+    assert!(added.projection.is_none());
+    assert_eq!(engine::size(&added), 0);
+    engine::add(&mut added, &resource()).unwrap();
+    assert_eq!(added.projection, pca.projection);
+    assert!(engine::index(first, &reduced(2, engine::ReductionMethod::Pca)).is_err());
+
+    assert!(engine::freeze(&pca).is_err());
+    let plain = engine::index(resource(), &Default::default()).unwrap();
+    assert!(engine::merge(&pca, &plain, Default::default()).is_err());
+    assert!(engine::merge(&pca, &added, engine::MergeConflict::Keep).is_ok());
+    assert!(engine::index(resource(), &reduced(0, engine::ReductionMethod::Pca)).is_err());
+}
+
+#[rstest]
+fn it_validates_embeddings(embedded_fixture: Embedded) {
+    let resource = embedded_fixture;
+    let rejected = |rejections: Vec<engine::Rejection>| -> Vec<(String, engine::Reason)> {
+        rejections
+            .into_iter()
+            .map(|rejection| (rejection.id, rejection.reason))
+            .collect()
+    };
+
+    let options = engine::IndexOptions {
+        normalize: true,
+        ..Default::default()
+    };
+    let mut index = engine::index(
+        Resource {
+            embeddings: vec![resource("a", vec![3.0, 0.0, 4.0])],
+        },
+        &options,
+    )
+    .unwrap();
+    assert_eq!(
+        *engine::find(&index, "a").unwrap().1.decode(),
+        [vec![0.6, 0.0, 0.8]]
+    );
+    // Queries are normalized too: [0, 0, 1] is at a squared distance of 0.4.
+    let query = engine::Query::Embeddings(vec![0.0, 0.0, 10.0]);
+    let neighbors = engine::search(&index, &query, 1, &Default::default()).unwrap();
+    assert_eq!(neighbors.len(), 1);
+    assert!((neighbors[0].score - 1.0 / 1.4).abs() < 1e-6);
+
+    let mut chunked = resource("chunked", vec![]);
+    chunked.chunks = Some(vec![vec![1.0, 0.0, 0.0], vec![0.0, f32::INFINITY, 0.0]]);
+    let rejections = engine::add(
+        &mut index,
+        &Resource {
+            embeddings: vec![
+                resource("b", vec![0.0, 2.0, 0.0]),
+                resource("nan", vec![1.0, f32::NAN, 0.0]),
+                resource("zero", vec![0.0; 3]),
+                resource("empty", vec![]),
+                resource("short", vec![1.0, 1.0]),
+                chunked,
+            ],
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        rejected(rejections.to_owned()),
+        vec![
+            ("nan".to_owned(), engine::Reason::NotFinite { position: 1 }),
+            ("zero".to_owned(), engine::Reason::Zero),
+            ("empty".to_owned(), engine::Reason::Empty),
+            (
+                "short".to_owned(),
+                engine::Reason::Dimension {
+                    expected: 3,
+                    found: 2
+                }
+            ),
+            (
+                "chunked".to_owned(),
+                engine::Reason::NotFinite { position: 1 }
+            ),
+        ]
+    );
+    assert_eq!(rejections[4].chunk, 1);
+    assert_eq!(engine::size(&index), 2);
+    assert_eq!(
+        *engine::find(&index, "b").unwrap().1.decode(),
+        [vec![0.0, 1.0, 0.0]]
+    );
+
+    // A new index fails on any invalid resource, naming it.
+    let error = engine::index(
+        Resource {
+            embeddings: vec![
+                resource("a", vec![1.0; 4]),
+                resource("large", vec![1.0; 800]),
+            ],
+        },
+        &Default::default(),
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("\"large\""));
+    let error = engine::index(
+        Resource {
+            embeddings: vec![resource("large", vec![1.0; 800])],
+        },
+        &Default::default(),
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("at most 768"));
+
+    // Truncated embeddings only need to be long enough.
+    let truncated = engine::IndexOptions {
+        reduction: Some(engine::Reduction {
+            dims: 4,
+            method: engine::ReductionMethod::Truncate,
+        }),
+        ..Default::default()
+    };
+    let mut index = engine::index(
+        Resource {
+            embeddings: vec![resource("a", vec![1.0; 1024])],
+        },
+        &truncated,
+    )
+    .unwrap();
+    let rejections = engine::add(
+        &mut index,
+        &Resource {
+            embeddings: vec![resource("b", vec![1.0; 8]), resource("c", vec![1.0; 3])],
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        rejected(rejections),
+        vec![(
+            "c".to_owned(),
+            engine::Reason::TooShort {
+                minimum: 4,
+                found: 3
+            }
+        )]
+    );
+}
+
+#[rstest]
+fn it_stores_embeddings_with_half_precision(
+    embedding_fixture: [[f32; 768]; 6],
+    question_fixture: [f32; 768],
+    embedded_fixture: Embedded,
+) {
+    // The fixtures, and mixes of them with a little noise so that there are
+    // enough close neighbours for the recall to tell something.
+    let mut random = Random::new(7);
+    let mut mix = || -> Vec<f32> {
+        let (a, b) = (random.below(6), random.below(6));
+        let t = random.next_f64() as f32;
+        (0..768)
+            .map(|i| {
+                let noise = (random.next_f64() as f32 - 0.5) * 0.01;
+                embedding_fixture[a][i] * t + embedding_fixture[b][i] * (1.0 - t) + noise
+            })
+            .collect()
+    };
+    let mut embeddings: Vec<Vec<f32>> = embedding_fixture.iter().map(|e| e.to_vec()).collect();
+    embeddings.extend((0..300).map(|_| mix()));
+    let mut queries = vec![question_fixture.to_vec()];
+    queries.extend((0..20).map(|_| mix()));
+
+    let resources: Vec<EmbeddedResource> = embeddings
+        .into_iter()
+        .enumerate()
+        .map(|(i, embeddings)| embedded_fixture(&i.to_string(), embeddings))
+        .collect();
+    let index_with = |precision| {
+        let options = engine::IndexOptions {
+            precision,
+            ..Default::default()
+        };
+        engine::index(
+            Resource {
+                embeddings: resources.clone(),
+            },
+            &options,
+        )
+        .unwrap()
+    };
+    let top = |index: &engine::Index, query: &[f32]| -> Vec<String> {
+        let query = engine::Query::Embeddings(query.to_vec());
+        engine::search(index, &query, 10, &Default::default())
+            .unwrap()
+            .into_iter()
+            .map(|neighbor| neighbor.document.id)
+            .collect()
+    };
+
+    let exact = index_with(engine::Precision::F32);
+    let serialized_exact = engine::serialize_index(&exact).unwrap();
+    // Trees are rebuilt from the embeddings rather than written.
+    assert!(!serialized_exact.contains("\"stems\""));
+    let restored = engine::deserialize_index(&serialized_exact).unwrap();
+    assert_eq!(engine::find(&restored, "0"), engine::find(&exact, "0"));
+    for precision in [engine::Precision::F16, engine::Precision::Bf16] {
+        let index = index_with(precision);
+        let (_, embeddings) = engine::find(&index, "0").unwrap();
+        // Held in 16 bits, not as rounded f32.
+        assert_eq!(embeddings.precision(), precision);
+        assert_eq!(embeddings.memory_size(), 768 * 2);
+        let chunks = embeddings.decode();
+        assert!(chunks[0].iter().all(|x| precision.round(*x) == *x));
+
+        let found: usize = queries
+            .iter()
+            .map(|query| {
+                let expected = top(&exact, query);
+                let actual = top(&index, query);
+                actual.iter().filter(|id| expected.contains(id)).count()
+            })
+            .sum();
+        let recall = found as f32 / (queries.len() * 10) as f32;
+        assert!(recall >= 0.95, "{:?} recall is {}", precision, recall);
+        assert_eq!(
+            top(&index, &question_fixture)[..3],
+            top(&exact, &question_fixture)[..3]
+        );
+
+        let serialized = engine::serialize_index(&index).unwrap();
+        assert!(serialized.contains(&format!("\"precision\":\"{:?}\"", precision).to_lowercase()));
+        assert!(serialized.len() * 5 < serialized_exact.len() * 3);
+        let restored = engine::deserialize_index(&serialized).unwrap();
+        engine::verify(&restored).unwrap();
+        assert_eq!(restored.options.precision, precision);
+        assert_eq!(engine::find(&restored, "0").unwrap().1.decode(), chunks);
+        for query in &queries {
+            assert_eq!(top(&restored, query), top(&index, query));
+        }
+    }
+}
+
+#[rstest]
+fn it_flushes_half_precision_embeddings_from_the_log(
+    resource_fixture: Resource,
+    question_fixture: [f32; 768],
+) {
+    let options = engine::IndexOptions {
+        precision: engine::Precision::F16,
+        ..Default::default()
+    };
+    let mut index = engine::index(Resource { embeddings: vec![] }, &options).unwrap();
+    engine::add(&mut index, &resource_fixture).unwrap();
+    assert_eq!(index.wal.len(), 6);
+    let (_, logged) = engine::find(&index, "0").unwrap();
+    // The log holds the embeddings in 16 bits like the segments.
+    assert_eq!(logged.precision(), engine::Precision::F16);
+    let logged = logged.decode().into_owned();
+    assert!(logged[0]
+        .iter()
+        .all(|x| engine::Precision::F16.round(*x) == *x));
+
+    let query = engine::Query::Embeddings(question_fixture.to_vec());
+    let top = |index: &engine::Index| -> Vec<String> {
+        engine::search(index, &query, 6, &Default::default())
+            .unwrap()
+            .into_iter()
+            .map(|neighbor| neighbor.document.id)
+            .collect()
+    };
+    let expected = top(&index);
+
+    // The log is packed like the segments, read back as it was written, and
+    // flushed to a packed segment.
+    let serialized = engine::serialize_index(&index).unwrap();
+    assert!(serialized.contains("\"packed\""));
+    assert!(!serialized.contains("[["));
+    let mut restored = engine::deserialize_index(&serialized).unwrap();
+    assert_eq!(*engine::find(&restored, "0").unwrap().1.decode(), logged);
+    engine::flush(&mut restored).unwrap();
+    assert!(restored.wal.is_empty());
+    assert_eq!(restored.segments.len(), 1);
+    assert_eq!(*engine::find(&restored, "0").unwrap().1.decode(), logged);
+    assert_eq!(top(&restored), expected);
+
+    let serialized = engine::serialize_index(&restored).unwrap();
+    assert!(serialized.contains("\"packed\""));
+    let flushed = engine::deserialize_index(&serialized).unwrap();
+    engine::verify(&flushed).unwrap();
+    assert_eq!(*engine::find(&flushed, "0").unwrap().1.decode(), logged);
+    assert_eq!(top(&flushed), expected);
+}
+
+// A tree of the chunks with the default options.
+fn tree_of(chunks: &[((u64, u32), &[f32])]) -> super::tree::Tree {
+    let points = chunks.iter().map(|(point, _)| *point).collect();
+    let dimensions = chunks
+        .iter()
+        .map(|(_, chunk)| chunk.len())
+        .max()
+        .unwrap_or(0);
+    super::tree::Tree::build(
+        points,
+        dimensions,
+        &Default::default(),
+        |point, dimension| chunks[point].1.get(dimension).copied().unwrap_or(0.0),
+    )
+    .unwrap()
+}
+
+fn server_resource(index: &engine::Index, id: &str) -> EmbeddedResource {
+    let (document, chunks) = engine::find(index, id).unwrap();
+    EmbeddedResource {
+        id: document.id.to_owned(),
+        title: document.title.to_owned(),
+        url: document.url.to_owned(),
+        embeddings: chunks.decode()[0].to_owned(),
+        chunks: None,
+        metadata: Some(document.metadata.0.to_owned()),
+    }
 }
@@ -0,0 +1,265 @@
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::mem::size_of;
+
+use super::bulk::{self, Stem, LEAF};
+use super::duplicates::DuplicatePolicy;
+use super::precision::Precision;
+use super::reduction::Reduction;
+
+// Larger leaves make shallower trees, but more distances to compute in each
+// leaf a search reaches.
+pub const SUPPORTED_BUCKET_SIZES: [usize; 3] = [8, 16, 32];
+
+// Width of the node indices of the trees, which caps the number of leaves: with
+// "u16", half of the indices address the stems and the other half the leaves.
+// "u32" lifts the cap.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexWidth {
+    #[default]
+    U16,
+    U32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct IndexOptions {
+    // Number of points held by each leaf of the trees.
+    pub bucket_size: usize,
+    pub index: IndexWidth,
+    // What adds do with documents within "duplicate_threshold", in euclidean
+    // distance, of another document.
+    #[serde(default, skip_serializing_if = "DuplicatePolicy::is_keep")]
+    pub duplicates: DuplicatePolicy,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub duplicate_threshold: f32,
+    // Reduces embeddings to fewer dimensions before they are indexed. Queries
+    // are reduced the same way.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reduction: Option<Reduction>,
+    // Scales embeddings to unit length when they are indexed.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub normalize: bool,
+    // Precision the embeddings are stored with.
+    #[serde(default, skip_serializing_if = "Precision::is_f32")]
+    pub precision: Precision,
+}
+
+fn is_zero(value: &f32) -> bool {
+    *value == 0.0
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+impl Default for IndexOptions {
+    fn default() -> Self {
+        IndexOptions {
+            bucket_size: 32,
+            index: IndexWidth::U16,
+            duplicates: DuplicatePolicy::Keep,
+            duplicate_threshold: 0.0,
+            reduction: None,
+            normalize: false,
+            precision: Precision::F32,
+        }
+    }
+}
+
+// Leaves of a tree built by median splits from "points" points.
+fn leaves_for(points: usize, bucket_size: usize) -> usize {
+    if points <= bucket_size {
+        return 1;
+    }
+    let half = points / 2;
+    leaves_for(half, bucket_size) + leaves_for(points - half, bucket_size)
+}
+
+impl IndexOptions {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !SUPPORTED_BUCKET_SIZES.contains(&self.bucket_size) {
+            bail!(
+                "unsupported bucket size {}, expected one of {:?}",
+                self.bucket_size,
+                SUPPORTED_BUCKET_SIZES
+            );
+        }
+        if !(self.duplicate_threshold >= 0.0 && self.duplicate_threshold.is_finite()) {
+            bail!(
+                "invalid duplicate threshold {}, expected a finite distance of at least 0",
+                self.duplicate_threshold
+            );
+        }
+        if let Some(reduction) = self.reduction {
+            if !(1..=768).contains(&reduction.dims) {
+                bail!(
+                    "invalid reduction to {} dimensions, expected 1 to 768",
+                    reduction.dims
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Node indices at or above this offset point into the leaves.
+    pub fn leaf_offset(&self) -> u64 {
+        match self.index {
+            IndexWidth::U16 => (u16::MAX >> 1) as u64,
+            IndexWidth::U32 => (u32::MAX >> 1) as u64,
+        }
+    }
+
+    fn max_index(&self) -> u64 {
+        match self.index {
+            IndexWidth::U16 => u16::MAX as u64,
+            IndexWidth::U32 => u32::MAX as u64,
+        }
+    }
+
+    // Checks that a tree of "points" points can be addressed with these options,
+    // before anything is built.
+    pub fn check_capacity(&self, points: usize) -> anyhow::Result<()> {
+        self.validate()?;
+
+        let leaves = leaves_for(points, self.bucket_size) as u64;
+        let max_leaves = self.max_index() - self.leaf_offset() + 1;
+        if leaves > max_leaves || leaves - 1 > self.leaf_offset() {
+            bail!(
+                "{} points exceed the capacity of a tree with bucket size {} and {:?} indices: \
+                 it needs {} leaves but at most {} can be addressed. Use a larger bucket size \
+                 or the u32 index width",
+                points,
+                self.bucket_size,
+                self.index,
+                leaves,
+                max_leaves
+            );
+        }
+        Ok(())
+    }
+}
+
+// A k-d tree of the chunks of a segment, built by `bulk::build`. Its points only
+// refer to the chunks: the coordinates are read from the embeddings of the
+// segment, so that every chunk is held once.
+#[derive(Debug, Clone)]
+pub struct Tree {
+    pub bucket_size: usize,
+    pub index: IndexWidth,
+    pub stems: Vec<Stem>,
+    // Per leaf: first point and number of points.
+    pub leaves: Vec<(u32, u32)>,
+    // Document and position of the chunk of every point, in the order of the
+    // tree.
+    pub points: Vec<(u64, u32)>,
+    pub root: u32,
+}
+
+impl Tree {
+    // A tree of the chunks, given by their document and position, whose
+    // coordinates are read by "coordinate" from the position of the point in
+    // "points" and the dimension.
+    pub fn build(
+        points: Vec<(u64, u32)>,
+        dimensions: usize,
+        options: &IndexOptions,
+        coordinate: impl Fn(usize, usize) -> f32,
+    ) -> anyhow::Result<Tree> {
+        options.check_capacity(points.len())?;
+
+        let bulk = bulk::build(points.len(), dimensions, options.bucket_size, coordinate);
+
+        Ok(Tree {
+            bucket_size: options.bucket_size,
+            index: options.index,
+            stems: bulk.stems,
+            leaves: bulk.leaves,
+            points: bulk.order.iter().map(|i| points[*i]).collect(),
+            root: bulk.root,
+        })
+    }
+
+    pub fn options(&self) -> IndexOptions {
+        // Only the options of the tree itself, the others keep their defaults.
+        IndexOptions {
+            bucket_size: self.bucket_size,
+            index: self.index,
+            ..IndexOptions::default()
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.points.len()
+    }
+
+    // Points of the leaf the node points to, if it's a leaf.
+    pub fn leaf(&self, node: u32) -> Option<&[(u64, u32)]> {
+        if node & LEAF == 0 {
+            return None;
+        }
+        let (start, len) = *self.leaves.get((node & !LEAF) as usize)?;
+
+        self.points
+            .get(start as usize..(start as usize).checked_add(len as usize)?)
+    }
+
+    // Walks the tree nearest side first. "visit" is given the points of every
+    // leaf reached and returns the squared distance past which the nodes left
+    // are skipped.
+    pub fn walk(&self, query: &[f32], mut visit: impl FnMut(&[(u64, u32)]) -> f32) {
+        let mut bound = f32::INFINITY;
+        // Nodes left to visit, with a lower bound of their distance to the query.
+        let mut stack = vec![(self.root, 0.0)];
+
+        while let Some((node, distance)) = stack.pop() {
+            if distance > bound {
+                continue;
+            }
+            if let Some(points) = self.leaf(node) {
+                bound = visit(points);
+                continue;
+            }
+            let Some(stem) = self.stems.get(node as usize) else {
+                continue;
+            };
+
+            let coordinate = query.get(stem.dimension as usize).copied().unwrap_or(0.0);
+            let difference = coordinate - stem.split;
+            let (near, far) = if difference < 0.0 {
+                (stem.left, stem.right)
+            } else {
+                (stem.right, stem.left)
+            };
+            stack.push((far, f32::max(distance, difference * difference)));
+            stack.push((near, distance));
+        }
+    }
+
+    // Depth of every leaf, counting the root as depth 0.
+    pub fn leaf_depths(&self) -> Vec<usize> {
+        let mut depths = vec![];
+        if self.points.is_empty() {
+            return depths;
+        }
+        let mut stack = vec![(self.root, 0)];
+
+        while let Some((node, depth)) = stack.pop() {
+            match self.stems.get(node as usize) {
+                Some(stem) if node & LEAF == 0 => {
+                    stack.push((stem.left, depth + 1));
+                    stack.push((stem.right, depth + 1));
+                }
+                _ => depths.push(depth),
+            }
+        }
+
+        depths
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.stems.len() * size_of::<Stem>()
+            + self.leaves.len() * size_of::<(u32, u32)>()
+            + self.points.len() * size_of::<(u64, u32)>()
+    }
+}
@@ -0,0 +1,188 @@
+use anyhow::bail;
+use std::fmt;
+
+use super::engine::Index;
+use super::reduction::ReductionMethod;
+use super::segment::Entry;
+use super::stats::dimension;
+use super::tree::IndexOptions;
+
+// Most dimensions the trees hold.
+const MAX_DIMENSIONS: usize = 768;
+
+// Why the embeddings of a resource can't be indexed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Empty,
+    // A coordinate is NaN or infinite.
+    NotFinite { position: usize },
+    // Every coordinate is 0, so the embeddings have no direction.
+    Zero,
+    // The length differs from the other embeddings of the index.
+    Dimension { expected: usize, found: usize },
+    // Too short to be truncated to the dimensions of the index.
+    TooShort { minimum: usize, found: usize },
+    // Longer than the trees hold, without a reduction.
+    TooLarge { maximum: usize, found: usize },
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Empty => write!(f, "the embeddings are empty"),
+            Reason::NotFinite { position } => {
+                write!(f, "coordinate {} is not a finite number", position)
+            }
+            Reason::Zero => write!(f, "every coordinate is 0"),
+            Reason::Dimension { expected, found } => {
+                write!(f, "expected {} dimensions, found {}", expected, found)
+            }
+            Reason::TooShort { minimum, found } => {
+                write!(
+                    f,
+                    "expected at least {} dimensions, found {}",
+                    minimum, found
+                )
+            }
+            Reason::TooLarge { maximum, found } => {
+                write!(
+                    f,
+                    "expected at most {} dimensions, found {}",
+                    maximum, found
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    pub id: String,
+    // Position of the first invalid chunk of the resource.
+    pub chunk: usize,
+    pub reason: Reason,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "resource \"{}\", chunk {}: {}",
+            self.id, self.chunk, self.reason
+        )
+    }
+}
+
+// Dimension of the embeddings the index takes, when it's known. Embeddings are
+// stored reduced, so with PCA it's the one the components were learned from.
+pub fn expected_dimension(index: &Index) -> Option<usize> {
+    match index.options.reduction.map(|reduction| reduction.method) {
+        None => Some(dimension(index)).filter(|dimension| *dimension > 0),
+        Some(ReductionMethod::Pca) => index
+            .projection
+            .as_ref()
+            .map(|projection| projection.mean.len()),
+        // Matryoshka embeddings of any length can be truncated.
+        Some(ReductionMethod::Truncate) => None,
+    }
+}
+
+// Splits the entries into the valid ones and the rejected ones. Without an
+// expected dimension, the first valid entry sets it. Valid entries are
+// normalized if the options say so.
+pub fn validate(
+    options: &IndexOptions,
+    dimension: Option<usize>,
+    entries: Vec<Entry>,
+) -> (Vec<Entry>, Vec<Rejection>) {
+    let truncated = options
+        .reduction
+        .is_some_and(|reduction| reduction.method == ReductionMethod::Truncate);
+    let mut dimension = dimension;
+    let mut accepted: Vec<Entry> = vec![];
+    let mut rejected: Vec<Rejection> = vec![];
+
+    for (id, document, mut chunks) in entries {
+        let expected = match truncated {
+            true => None,
+            false => dimension.or(chunks.first().map(Vec::len)),
+        };
+        let invalid = chunks.iter().enumerate().find_map(|(i, chunk)| {
+            check(options, expected, chunk)
+                .err()
+                .map(|reason| (i, reason))
+        });
+
+        match invalid {
+            Some((chunk, reason)) => rejected.push(Rejection {
+                id: document.id,
+                chunk,
+                reason,
+            }),
+            None => {
+                dimension = expected;
+                if options.normalize {
+                    for chunk in chunks.iter_mut() {
+                        *chunk = normalize(chunk);
+                    }
+                }
+                accepted.push((id, document, chunks));
+            }
+        }
+    }
+
+    (accepted, rejected)
+}
+
+// Fails with every rejection, if there are any.
+pub fn accept_all(rejected: &[Rejection]) -> anyhow::Result<()> {
+    if rejected.is_empty() {
+        return Ok(());
+    }
+
+    let reasons: Vec<String> = rejected.iter().map(Rejection::to_string).collect();
+    bail!(
+        "{} resources were rejected: {}",
+        rejected.len(),
+        reasons.join("; ")
+    );
+}
+
+fn check(options: &IndexOptions, expected: Option<usize>, chunk: &[f32]) -> Result<(), Reason> {
+    let found = chunk.len();
+    if found == 0 {
+        return Err(Reason::Empty);
+    }
+    if let Some(position) = chunk.iter().position(|x| !x.is_finite()) {
+        return Err(Reason::NotFinite { position });
+    }
+    if chunk.iter().all(|x| *x == 0.0) {
+        return Err(Reason::Zero);
+    }
+
+    match (options.reduction, expected) {
+        (Some(reduction), _)
+            if reduction.method == ReductionMethod::Truncate && found < reduction.dims =>
+        {
+            Err(Reason::TooShort {
+                minimum: reduction.dims,
+                found,
+            })
+        }
+        (_, Some(expected)) if found != expected => Err(Reason::Dimension { expected, found }),
+        (None, _) if found > MAX_DIMENSIONS => Err(Reason::TooLarge {
+            maximum: MAX_DIMENSIONS,
+            found,
+        }),
+        _ => Ok(()),
+    }
+}
+
+// The vector scaled to unit length. Zero vectors stay as they are.
+pub fn normalize(vector: &[f32]) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    match norm > 0.0 {
+        true => vector.iter().map(|x| x / norm).collect(),
+        false => vector.to_vec(),
+    }
+}
@@ -4,6 +4,11 @@ mod wasm;
 
 pub use wasm::*;
 
+// Starts the web workers used by the "threads" feature. Must be awaited once
+// before anything else is called.
+#[cfg(all(feature = "threads", target_arch = "wasm32"))]
+pub use wasm_bindgen_rayon::init_thread_pool;
+
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
 #[cfg(feature = "wee_alloc")]
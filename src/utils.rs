@@ -1,3 +1,6 @@
+use crate::engine;
+use wasm_bindgen::JsError;
+
 pub fn set_panic_hook() {
     // When the `console_error_panic_hook` feature is enabled, we can call the
     // `set_panic_hook` function at least once during initialization, and then
@@ -8,3 +11,28 @@ pub fn set_panic_hook() {
     #[cfg(feature = "console_error_panic_hook")]
     console_error_panic_hook::set_once();
 }
+
+// Turns an engine error into a JS Error with the same message, so that callers
+// get a catchable exception instead of a panic.
+pub fn js_error(error: anyhow::Error) -> JsError {
+    JsError::new(&format!("{:#}", error))
+}
+
+// A version given by JS, which must be an integer JS numbers hold exactly.
+pub fn version_from_js(version: f64) -> Result<u64, JsError> {
+    if !(version >= 0.0 && version <= engine::MAX_VERSION as f64 && version.fract() == 0.0) {
+        return Err(JsError::new(&format!(
+            "invalid version {}, expected an integer from 0 to {}",
+            version,
+            engine::MAX_VERSION
+        )));
+    }
+    Ok(version as u64)
+}
+
+// Versions are read and bumped up to `engine::MAX_VERSION`, so they fit a JS
+// number exactly.
+pub fn version_to_js(version: u64) -> f64 {
+    debug_assert!(version <= engine::MAX_VERSION);
+    version as f64
+}
@@ -0,0 +1,100 @@
+use crate::{
+    engine,
+    utils::{js_error, set_panic_hook},
+    IndexOptions, Resource, Voy,
+};
+use js_sys::{Function, Promise, Reflect};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "ReadableStream<Resource>")]
+    pub type ResourceStream;
+
+    #[wasm_bindgen(method, js_name = getReader)]
+    fn get_reader(this: &ResourceStream) -> StreamReader;
+
+    type StreamReader;
+
+    #[wasm_bindgen(method)]
+    fn read(this: &StreamReader) -> Promise;
+
+    #[wasm_bindgen(method, js_name = releaseLock)]
+    fn release_lock(this: &StreamReader);
+}
+
+// Reports the number of resources pushed so far to a JS callback.
+fn progress(callback: Function) -> impl FnMut(usize) {
+    move |pushed| {
+        let _ = callback.call1(&JsValue::NULL, &JsValue::from(pushed));
+    }
+}
+
+#[wasm_bindgen]
+pub struct IndexBuilder {
+    builder: engine::IndexBuilder,
+}
+
+#[wasm_bindgen]
+impl IndexBuilder {
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        progress: Option<Function>,
+        options: Option<IndexOptions>,
+    ) -> Result<IndexBuilder, JsError> {
+        set_panic_hook();
+
+        let options: engine::IndexOptions = options.unwrap_or_default().into();
+        let builder = engine::IndexBuilder::new()
+            .with_options(options)
+            .map_err(js_error)?;
+        let builder = match progress {
+            Some(callback) => builder.on_progress(self::progress(callback)),
+            None => builder,
+        };
+
+        Ok(IndexBuilder { builder })
+    }
+
+    // Adds a batch of resources and returns the number of resources pushed so far.
+    pub fn push(&mut self, batch: Resource) -> usize {
+        self.builder.push(batch.embeddings)
+    }
+
+    pub fn size(&self) -> usize {
+        self.builder.len()
+    }
+
+    // Builds the index. The builder can't be used afterwards.
+    pub fn finish(self) -> Result<Voy, JsError> {
+        let index = self.builder.finish().map_err(js_error)?;
+        Ok(Voy::from(index))
+    }
+}
+
+// Builds a Voy instance from a stream of resource batches. Only the batch being
+// read is held on top of the index under construction.
+#[wasm_bindgen]
+pub async fn index_stream(
+    stream: ResourceStream,
+    progress: Option<Function>,
+    options: Option<IndexOptions>,
+) -> Result<Voy, JsValue> {
+    let mut builder = IndexBuilder::new(progress, options)?;
+    let reader = stream.get_reader();
+
+    loop {
+        let chunk = JsFuture::from(reader.read()).await?;
+        if Reflect::get(&chunk, &JsValue::from_str("done"))?.is_truthy() {
+            break;
+        }
+
+        let value = Reflect::get(&chunk, &JsValue::from_str("value"))?;
+        let batch: Resource = serde_wasm_bindgen::from_value(value)?;
+        builder.push(batch);
+    }
+    reader.release_lock();
+
+    Ok(builder.finish()?)
+}
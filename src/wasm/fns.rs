@@ -1,75 +1,316 @@
 use crate::{
-    engine, utils::set_panic_hook, Neighbor, NumberOfResult, Query, Resource, SearchResult,
-    SerializedIndex,
+    engine,
+    utils::{js_error, set_panic_hook, version_from_js, version_to_js},
+    BatchSearchResult, Clusters, Delta, Duplicates, EmbeddedResource, IndexOptions, IndexStats,
+    KnnGraph, MergeConflict, Neighbor, NumberOfResult, Queries, Query, Resource, SearchOptions,
+    SearchResult, SerializedIndex,
 };
+use std::convert::TryInto;
 use wasm_bindgen::prelude::*;
 
+// Reads an index serialized by any version of voy, migrating older formats.
+fn parse(index: &str) -> Result<engine::Index, JsError> {
+    engine::deserialize_index(index).map_err(js_error)
+}
+
+fn stringify(index: &engine::Index) -> Result<SerializedIndex, JsError> {
+    engine::serialize_index(index).map_err(js_error)
+}
+
 #[wasm_bindgen]
-pub fn index(resource: Resource) -> SerializedIndex {
+pub fn index(
+    resource: Resource,
+    options: Option<IndexOptions>,
+) -> Result<SerializedIndex, JsError> {
     set_panic_hook();
 
-    let index = engine::index(resource);
-    match index {
-        Ok(idx) => serde_json::to_string(&idx).unwrap(),
-        _ => "".to_owned(),
-    }
+    let options: engine::IndexOptions = options.unwrap_or_default().into();
+    let index = engine::index(resource, &options).map_err(js_error)?;
+
+    stringify(&index)
 }
 
 #[wasm_bindgen]
-pub fn search(index: SerializedIndex, query: Query, k: NumberOfResult) -> SearchResult {
+pub fn search(
+    index: SerializedIndex,
+    query: Query,
+    k: NumberOfResult,
+    options: Option<SearchOptions>,
+) -> Result<SearchResult, JsError> {
     set_panic_hook();
 
-    let index: engine::Index = serde_json::from_str(&index).unwrap();
+    let index = parse(&index)?;
     let query: engine::Query = engine::Query::Embeddings(query);
 
-    let neighbors = engine::search(&index, &query, k).unwrap();
-    let neighbors: Vec<Neighbor> = neighbors
+    let options: engine::SearchOptions =
+        options.unwrap_or_default().try_into().map_err(js_error)?;
+
+    let neighbors = engine::search(&index, &query, k, &options).map_err(js_error)?;
+    let neighbors: Vec<Neighbor> = neighbors.into_iter().map(Neighbor::from).collect();
+
+    Ok(SearchResult { neighbors })
+}
+
+#[wasm_bindgen]
+pub fn search_batch(
+    index: SerializedIndex,
+    queries: Queries,
+    k: NumberOfResult,
+    options: Option<SearchOptions>,
+) -> Result<BatchSearchResult, JsError> {
+    set_panic_hook();
+
+    let index = parse(&index)?;
+    let queries: Vec<engine::Query> = queries
+        .queries
+        .into_iter()
+        .map(engine::Query::Embeddings)
+        .collect();
+
+    let options: engine::SearchOptions =
+        options.unwrap_or_default().try_into().map_err(js_error)?;
+
+    let results = engine::search_batch(&index, &queries, k, &options).map_err(js_error)?;
+    let results: Vec<SearchResult> = results
         .into_iter()
-        .map(|x| Neighbor {
-            id: x.id,
-            title: x.title,
-            url: x.url,
+        .map(|neighbors| SearchResult {
+            neighbors: neighbors.into_iter().map(Neighbor::from).collect(),
         })
         .collect();
 
-    SearchResult { neighbors }
+    Ok(BatchSearchResult { results })
+}
+
+#[wasm_bindgen]
+pub fn search_similar_to(
+    index: SerializedIndex,
+    id: String,
+    k: NumberOfResult,
+    include_self: Option<bool>,
+) -> Result<SearchResult, JsError> {
+    set_panic_hook();
+
+    let index = parse(&index)?;
+    let include_self = include_self.unwrap_or(false);
+
+    let neighbors = engine::search_similar_to(&index, &id, k, include_self).map_err(js_error)?;
+    let neighbors: Vec<Neighbor> = neighbors.into_iter().map(Neighbor::from).collect();
+
+    Ok(SearchResult { neighbors })
 }
 
 #[wasm_bindgen]
-pub fn add(index: SerializedIndex, resource: Resource) -> SerializedIndex {
+pub fn add(index: SerializedIndex, resource: Resource) -> Result<SerializedIndex, JsError> {
     set_panic_hook();
 
-    let mut index: engine::Index = serde_json::from_str(&index).unwrap();
-    engine::add(&mut index, &resource);
+    let mut index = parse(&index)?;
+    // Only the index is returned, so rejections fail the whole add.
+    let rejected = engine::add(&mut index, &resource).map_err(js_error)?;
+    engine::accept_all(&rejected).map_err(js_error)?;
 
-    serde_json::to_string(&index).unwrap()
+    stringify(&index)
 }
 
 #[wasm_bindgen]
-pub fn remove(index: SerializedIndex, resource: Resource) -> SerializedIndex {
+pub fn remove(index: SerializedIndex, resource: Resource) -> Result<SerializedIndex, JsError> {
     set_panic_hook();
 
-    let mut index: engine::Index = serde_json::from_str(&index).unwrap();
+    let mut index = parse(&index)?;
     engine::remove(&mut index, &resource);
 
-    serde_json::to_string(&index).unwrap()
+    stringify(&index)
 }
 
 #[wasm_bindgen]
-pub fn clear(index: SerializedIndex) -> SerializedIndex {
+pub fn clear(index: SerializedIndex) -> Result<SerializedIndex, JsError> {
     set_panic_hook();
 
-    let mut index: engine::Index = serde_json::from_str(&index).unwrap();
+    let mut index = parse(&index)?;
     engine::clear(&mut index);
 
-    serde_json::to_string(&index).unwrap()
+    stringify(&index)
+}
+
+#[wasm_bindgen]
+pub fn compact(index: SerializedIndex) -> Result<SerializedIndex, JsError> {
+    set_panic_hook();
+
+    let mut index = parse(&index)?;
+    engine::compact(&mut index).map_err(js_error)?;
+
+    stringify(&index)
+}
+
+#[wasm_bindgen]
+pub fn size(index: SerializedIndex) -> Result<usize, JsError> {
+    set_panic_hook();
+
+    let index = parse(&index)?;
+
+    Ok(engine::size(&index))
+}
+
+#[wasm_bindgen]
+pub fn get(index: SerializedIndex, id: String) -> Result<Option<EmbeddedResource>, JsError> {
+    set_panic_hook();
+
+    let index = parse(&index)?;
+
+    Ok(engine::find(&index, &id)
+        .map(|(document, embeddings)| EmbeddedResource::new(document, &embeddings.decode())))
+}
+
+#[wasm_bindgen]
+pub fn has(index: SerializedIndex, id: String) -> Result<bool, JsError> {
+    set_panic_hook();
+
+    let index = parse(&index)?;
+
+    Ok(engine::find(&index, &id).is_some())
+}
+
+#[wasm_bindgen]
+pub fn ids(index: SerializedIndex) -> Result<Vec<String>, JsError> {
+    set_panic_hook();
+
+    let index = parse(&index)?;
+
+    Ok(engine::documents(&index, 0, usize::MAX)
+        .into_iter()
+        .map(|(document, _)| document.id.to_owned())
+        .collect())
+}
+
+#[wasm_bindgen]
+pub fn documents(
+    index: SerializedIndex,
+    offset: usize,
+    limit: usize,
+    include_embeddings: Option<bool>,
+) -> Result<Resource, JsError> {
+    set_panic_hook();
+
+    let index = parse(&index)?;
+    let include_embeddings = include_embeddings.unwrap_or(false);
+    let embeddings = engine::documents(&index, offset, limit)
+        .into_iter()
+        .map(|(document, embeddings)| {
+            if include_embeddings {
+                EmbeddedResource::new(document, &embeddings.decode())
+            } else {
+                EmbeddedResource::new(document, &[])
+            }
+        })
+        .collect();
+
+    Ok(Resource { embeddings })
+}
+
+#[wasm_bindgen]
+pub fn stats(index: SerializedIndex) -> Result<IndexStats, JsError> {
+    set_panic_hook();
+
+    let index = parse(&index)?;
+
+    Ok(engine::stats(&index).map_err(js_error)?.into())
+}
+
+#[wasm_bindgen]
+pub fn verify(index: SerializedIndex) -> Result<(), JsError> {
+    set_panic_hook();
+
+    let index = parse(&index)?;
+
+    engine::verify(&index).map_err(js_error)
+}
+
+#[wasm_bindgen]
+pub fn freeze(index: SerializedIndex) -> Result<Vec<u8>, JsError> {
+    set_panic_hook();
+
+    let index = parse(&index)?;
+
+    engine::freeze(&index).map_err(js_error)
+}
+
+#[wasm_bindgen]
+pub fn version(index: SerializedIndex) -> Result<f64, JsError> {
+    set_panic_hook();
+
+    let index = parse(&index)?;
+
+    Ok(version_to_js(index.changes.version))
+}
+
+#[wasm_bindgen]
+pub fn export_changes_since(index: SerializedIndex, version: f64) -> Result<Delta, JsError> {
+    set_panic_hook();
+
+    let index = parse(&index)?;
+
+    Ok(engine::export_changes_since(&index, version_from_js(version)?).into())
+}
+
+#[wasm_bindgen]
+pub fn apply_changes(index: SerializedIndex, delta: Delta) -> Result<SerializedIndex, JsError> {
+    set_panic_hook();
+
+    let mut index = parse(&index)?;
+    engine::apply_changes(&mut index, &delta.into()).map_err(js_error)?;
+
+    stringify(&index)
+}
+
+#[wasm_bindgen]
+pub fn merge(
+    a: SerializedIndex,
+    b: SerializedIndex,
+    conflict: Option<MergeConflict>,
+) -> Result<SerializedIndex, JsError> {
+    set_panic_hook();
+
+    let (a, b) = (parse(&a)?, parse(&b)?);
+    let conflict = conflict.map(Into::into).unwrap_or_default();
+    let merged = engine::merge(&a, &b, conflict).map_err(js_error)?;
+
+    stringify(&merged)
+}
+
+#[wasm_bindgen]
+pub fn find_duplicates(index: SerializedIndex, threshold: f32) -> Result<Duplicates, JsError> {
+    set_panic_hook();
+
+    let index = parse(&index)?;
+    let duplicates = engine::find_duplicates(&index, threshold).into();
+
+    Ok(duplicates)
+}
+
+#[wasm_bindgen]
+pub fn cluster(
+    index: SerializedIndex,
+    k: usize,
+    iterations: usize,
+    seed: u32,
+) -> Result<Clusters, JsError> {
+    set_panic_hook();
+
+    let index = parse(&index)?;
+    let clusters = engine::cluster(&index, k, iterations, seed as u64).map_err(js_error)?;
+
+    Ok(clusters.into())
 }
 
 #[wasm_bindgen]
-pub fn size(index: SerializedIndex) -> usize {
+pub fn knn_graph(
+    index: SerializedIndex,
+    k: usize,
+    threshold: Option<f32>,
+) -> Result<KnnGraph, JsError> {
     set_panic_hook();
 
-    let index: engine::Index = serde_json::from_str(&index).unwrap();
+    let index = parse(&index)?;
+    let graph = engine::knn_graph(&index, k, threshold).into();
 
-    engine::size(&index)
+    Ok(graph)
 }
@@ -0,0 +1,45 @@
+use crate::{
+    engine,
+    utils::{js_error, set_panic_hook},
+    Neighbor, NumberOfResult, Query, SearchOptions, SearchResult,
+};
+use std::convert::TryInto;
+use wasm_bindgen::prelude::*;
+
+// A read-only index searched straight from the bytes returned by "freeze",
+// without deserializing them first.
+#[wasm_bindgen]
+pub struct FrozenIndex {
+    index: engine::FrozenIndex<Vec<u8>>,
+}
+
+#[wasm_bindgen]
+impl FrozenIndex {
+    #[wasm_bindgen(constructor)]
+    pub fn new(bytes: Vec<u8>) -> Result<FrozenIndex, JsError> {
+        set_panic_hook();
+
+        let index = engine::FrozenIndex::open(bytes).map_err(js_error)?;
+        Ok(FrozenIndex { index })
+    }
+
+    pub fn search(
+        &self,
+        query: Query,
+        k: NumberOfResult,
+        options: Option<SearchOptions>,
+    ) -> Result<SearchResult, JsError> {
+        let query: engine::Query = engine::Query::Embeddings(query);
+        let options: engine::SearchOptions =
+            options.unwrap_or_default().try_into().map_err(js_error)?;
+
+        let neighbors = self.index.search(&query, k, &options).map_err(js_error)?;
+        let neighbors: Vec<Neighbor> = neighbors.into_iter().map(Neighbor::from).collect();
+
+        Ok(SearchResult { neighbors })
+    }
+
+    pub fn size(&self) -> usize {
+        self.index.size()
+    }
+}
@@ -1,7 +1,13 @@
+mod builder;
 mod fns;
+mod frozen;
+mod projection;
 mod types;
 mod voy;
 
+pub use builder::*;
 pub use fns::*;
+pub use frozen::*;
+pub use projection::*;
 pub use types::*;
 pub use voy::*;
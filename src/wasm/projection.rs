@@ -0,0 +1,81 @@
+use crate::{
+    engine,
+    utils::{js_error, set_panic_hook},
+    Coordinates, ProjectedResource, ProjectionMethod, Query, SerializedIndex,
+};
+use wasm_bindgen::prelude::*;
+
+// Resources of an index projected to a few dimensions, e.g. to plot them. Queries
+// can be projected into the same space.
+#[wasm_bindgen]
+pub struct Projection {
+    projection: engine::Projection,
+    resources: Vec<ProjectedResource>,
+    // The reduction of the index, which queries go through before they are
+    // projected like its resources were.
+    options: engine::IndexOptions,
+    reduction: Option<engine::Projection>,
+}
+
+impl Projection {
+    pub fn of(
+        index: &engine::Index,
+        dims: usize,
+        method: Option<ProjectionMethod>,
+        seed: Option<u32>,
+    ) -> Result<Projection, JsError> {
+        let method = match method.unwrap_or(ProjectionMethod::Pca) {
+            ProjectionMethod::Pca => engine::ProjectionMethod::Pca,
+            ProjectionMethod::Random => engine::ProjectionMethod::Random {
+                seed: seed.unwrap_or(0) as u64,
+            },
+        };
+        let (projection, coordinates) = engine::project(index, dims, method).map_err(js_error)?;
+        let resources = coordinates
+            .into_iter()
+            .map(|(document, coordinates)| ProjectedResource {
+                id: document.id.to_owned(),
+                coordinates,
+            })
+            .collect();
+
+        Ok(Projection {
+            projection,
+            resources,
+            options: index.options,
+            reduction: index.projection.as_deref().cloned(),
+        })
+    }
+}
+
+#[wasm_bindgen]
+impl Projection {
+    // Projects the resources of a serialized index.
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        index: SerializedIndex,
+        dims: usize,
+        method: Option<ProjectionMethod>,
+        seed: Option<u32>,
+    ) -> Result<Projection, JsError> {
+        set_panic_hook();
+
+        let index = engine::deserialize_index(&index).map_err(js_error)?;
+        Projection::of(&index, dims, method, seed)
+    }
+
+    pub fn dims(&self) -> usize {
+        self.projection.dims()
+    }
+
+    pub fn coordinates(&self) -> Coordinates {
+        Coordinates {
+            resources: self.resources.to_owned(),
+        }
+    }
+
+    pub fn project(&self, query: Query) -> Vec<f32> {
+        let query = engine::reduce(&self.options, self.reduction.as_ref(), &query);
+        self.projection.apply(&query)
+    }
+}
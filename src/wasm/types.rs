@@ -1,10 +1,22 @@
+use crate::engine;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
 use tsify::Tsify;
+use wasm_bindgen::prelude::*;
 
 pub type NumberOfResult = usize;
 pub type Query = Vec<f32>;
 pub type SerializedIndex = String;
 
+#[wasm_bindgen]
+extern "C" {
+    // A serialized index as returned by "serialize", or the bytes returned by
+    // "serialize_compressed".
+    #[wasm_bindgen(typescript_type = "string | Uint8Array")]
+    pub type SerializedInput;
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
 #[tsify(into_wasm_abi, from_wasm_abi)]
 pub struct EmbeddedResource {
@@ -12,6 +24,13 @@ pub struct EmbeddedResource {
     pub title: String,
     pub url: String,
     pub embeddings: Vec<f32>,
+    // Embeddings of each chunk of a long resource. When set, they are indexed
+    // instead of "embeddings" and searches return the resource once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Vec<Vec<f32>>>,
+    // Numeric fields that searches can boost the score with, e.g. a timestamp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<BTreeMap<String, f64>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Tsify)]
@@ -24,12 +43,53 @@ pub struct Resource {
     pub embeddings: Vec<EmbeddedResource>,
 }
 
+impl EmbeddedResource {
+    pub fn new(document: &engine::Document, chunks: &[Vec<f32>]) -> Self {
+        let (embeddings, chunks) = match chunks {
+            [] => (vec![], None),
+            [embeddings] => (embeddings.to_owned(), None),
+            chunks => (vec![], Some(chunks.to_vec())),
+        };
+
+        EmbeddedResource {
+            id: document.id.to_owned(),
+            title: document.title.to_owned(),
+            url: document.url.to_owned(),
+            embeddings,
+            chunks,
+            metadata: match document.metadata.is_empty() {
+                true => None,
+                false => Some(document.metadata.0.to_owned()),
+            },
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
 #[tsify(into_wasm_abi)]
 pub struct Neighbor {
     pub id: String,
     pub title: String,
     pub url: String,
+    pub chunk: usize,
+    // Similarity to the query after boosts, higher is better.
+    pub score: f32,
+    // Namespace the resource was found in, when searching a Voy instance.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+impl From<engine::Neighbor> for Neighbor {
+    fn from(neighbor: engine::Neighbor) -> Self {
+        Neighbor {
+            id: neighbor.document.id,
+            title: neighbor.document.title,
+            url: neighbor.document.url,
+            chunk: neighbor.chunk,
+            score: neighbor.score,
+            namespace: None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
@@ -37,3 +97,539 @@ pub struct Neighbor {
 pub struct SearchResult {
     pub neighbors: Vec<Neighbor>,
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct Queries {
+    pub queries: Vec<Vec<f32>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct BatchSearchResult {
+    // One result per query, in the order of the queries.
+    pub results: Vec<SearchResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Tsify)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    Max,
+    Mean,
+    Sum,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Tsify)]
+#[tsify(from_wasm_abi)]
+pub struct SearchOptions {
+    // How chunk scores add up to the score of a resource. Defaults to "max".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggregation: Option<Aggregation>,
+    // Number of best chunks per resource used by "mean" and "sum". Defaults to 3.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_chunks: Option<usize>,
+    // Namespaces to search in. Defaults to all namespaces of a Voy instance.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespaces: Option<Vec<String>>,
+    // Adjust the scores by the metadata of the resources, applied in order.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boosts: Option<Vec<Boost>>,
+    // Ids of resources that are never returned.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude_ids: Option<Vec<String>>,
+    // When set, only resources with these ids are returned.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow_ids: Option<Vec<String>>,
+}
+
+impl TryFrom<SearchOptions> for engine::SearchOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(options: SearchOptions) -> anyhow::Result<Self> {
+        let default = engine::SearchOptions::default();
+
+        Ok(engine::SearchOptions {
+            aggregation: match options.aggregation {
+                Some(Aggregation::Max) => engine::Aggregation::Max,
+                Some(Aggregation::Mean) => engine::Aggregation::Mean,
+                Some(Aggregation::Sum) => engine::Aggregation::Sum,
+                None => default.aggregation,
+            },
+            top_chunks: options.top_chunks.unwrap_or(default.top_chunks),
+            boosts: options
+                .boosts
+                .unwrap_or_default()
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<anyhow::Result<_>>()?,
+            exclude_ids: options
+                .exclude_ids
+                .unwrap_or_default()
+                .into_iter()
+                .collect(),
+            allow_ids: options.allow_ids.map(|ids| ids.into_iter().collect()),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Boost {
+    // Halves the score every "half_life" that the timestamp in "field" is older
+    // than "now". Resources without the field keep their score.
+    Decay {
+        field: String,
+        now: f64,
+        half_life: f64,
+    },
+    // Multiplies the score by the field, 1 for resources without it.
+    Weight {
+        field: String,
+    },
+    // Adds the fields multiplied by their weights. Missing fields count as 0.
+    Linear {
+        weights: BTreeMap<String, f64>,
+    },
+}
+
+impl TryFrom<Boost> for engine::Boost {
+    type Error = anyhow::Error;
+
+    fn try_from(boost: Boost) -> anyhow::Result<Self> {
+        Ok(match boost {
+            Boost::Decay {
+                field,
+                now,
+                half_life,
+            } => engine::Boost::decay(field, now, half_life)?,
+            Boost::Weight { field } => engine::Boost::Weight { field },
+            Boost::Linear { weights } => engine::Boost::Linear { weights },
+        })
+    }
+}
+
+// What to do when both indexes of a merge hold a resource with the same id:
+// fail, keep the resource of the first index or replace it with the second's.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Tsify)]
+#[tsify(from_wasm_abi)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeConflict {
+    Fail,
+    Keep,
+    Replace,
+}
+
+impl From<MergeConflict> for engine::MergeConflict {
+    fn from(conflict: MergeConflict) -> Self {
+        match conflict {
+            MergeConflict::Fail => engine::MergeConflict::Fail,
+            MergeConflict::Keep => engine::MergeConflict::Keep,
+            MergeConflict::Replace => engine::MergeConflict::Replace,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Tsify)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexWidth {
+    U16,
+    U32,
+}
+
+// Fail the add, or merge the metadata of the duplicate into the resource it
+// duplicates instead of adding it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Tsify)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+    Keep,
+    Reject,
+    Merge,
+}
+
+// "f16" and "bf16" halve the precision of the stored embeddings, which more
+// than halves the size of serialized indexes. The size in memory stays the same.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Tsify)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    F32,
+    F16,
+    Bf16,
+}
+
+// "truncate" keeps the first dimensions and renormalizes, for Matryoshka
+// embeddings. "pca" projects onto the principal components of the first
+// resources indexed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Tsify)]
+#[serde(rename_all = "lowercase")]
+pub enum ReductionMethod {
+    Truncate,
+    Pca,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Tsify)]
+pub struct Reduction {
+    pub dims: usize,
+    pub method: ReductionMethod,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Tsify)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionReason {
+    Empty,
+    NotFinite,
+    Zero,
+    Dimension,
+    TooShort,
+    TooLarge,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
+pub struct RejectedResource {
+    pub id: String,
+    pub chunk: usize,
+    pub reason: RejectionReason,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct Rejections {
+    // Resources that weren't added, in the order they were given.
+    pub rejected: Vec<RejectedResource>,
+}
+
+impl From<Vec<engine::Rejection>> for Rejections {
+    fn from(rejections: Vec<engine::Rejection>) -> Self {
+        let rejected = rejections
+            .into_iter()
+            .map(|rejection| RejectedResource {
+                reason: match rejection.reason {
+                    engine::Reason::Empty => RejectionReason::Empty,
+                    engine::Reason::NotFinite { .. } => RejectionReason::NotFinite,
+                    engine::Reason::Zero => RejectionReason::Zero,
+                    engine::Reason::Dimension { .. } => RejectionReason::Dimension,
+                    engine::Reason::TooShort { .. } => RejectionReason::TooShort,
+                    engine::Reason::TooLarge { .. } => RejectionReason::TooLarge,
+                },
+                message: rejection.reason.to_string(),
+                id: rejection.id,
+                chunk: rejection.chunk,
+            })
+            .collect();
+
+        Rejections { rejected }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct Duplicates {
+    // Ids of the resources of each group, every one within the threshold of
+    // another resource of its group.
+    pub groups: Vec<Vec<String>>,
+}
+
+impl From<Vec<Vec<&engine::Document>>> for Duplicates {
+    fn from(groups: Vec<Vec<&engine::Document>>) -> Self {
+        Duplicates {
+            groups: groups
+                .into_iter()
+                .map(|group| {
+                    group
+                        .into_iter()
+                        .map(|document| document.id.to_owned())
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct Cluster {
+    pub centroid: Vec<f32>,
+    // Ids of the resources of the cluster.
+    pub members: Vec<String>,
+    // Id of the resource closest to the centroid, absent for an empty cluster.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nearest: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct Clusters {
+    pub clusters: Vec<Cluster>,
+}
+
+impl From<Vec<engine::Cluster<'_>>> for Clusters {
+    fn from(clusters: Vec<engine::Cluster>) -> Self {
+        Clusters {
+            clusters: clusters
+                .into_iter()
+                .map(|cluster| Cluster {
+                    centroid: cluster.centroid,
+                    members: cluster
+                        .members
+                        .into_iter()
+                        .map(|document| document.id.to_owned())
+                        .collect(),
+                    nearest: cluster.nearest.map(|document| document.id.to_owned()),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct Edge {
+    pub id: String,
+    // Euclidean distance between the closest chunks of the two resources.
+    pub distance: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct KnnNode {
+    pub id: String,
+    // Nearest resources, closest first.
+    pub neighbors: Vec<Edge>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct KnnGraph {
+    pub nodes: Vec<KnnNode>,
+}
+
+impl From<Vec<engine::Node<'_>>> for KnnGraph {
+    fn from(nodes: Vec<engine::Node>) -> Self {
+        KnnGraph {
+            nodes: nodes
+                .into_iter()
+                .map(|node| KnnNode {
+                    id: node.document.id.to_owned(),
+                    neighbors: node
+                        .neighbors
+                        .into_iter()
+                        .map(|(document, distance)| Edge {
+                            id: document.id.to_owned(),
+                            distance,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+// Principal component analysis, or a random Gaussian matrix seeded by "seed".
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Tsify)]
+#[tsify(from_wasm_abi)]
+#[serde(rename_all = "lowercase")]
+pub enum ProjectionMethod {
+    Pca,
+    Random,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct ProjectedResource {
+    pub id: String,
+    pub coordinates: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct Coordinates {
+    // Ordered by id.
+    pub resources: Vec<ProjectedResource>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Tsify)]
+#[tsify(from_wasm_abi)]
+pub struct IndexOptions {
+    // Number of points per leaf of the trees: 8, 16 or 32. Defaults to 32.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket_size: Option<usize>,
+    // Width of the node indices. "u32" allows larger trees. Defaults to "u16".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<IndexWidth>,
+    // What "add" does with resources within "duplicate_threshold" of another
+    // resource. Defaults to "keep".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicates: Option<DuplicatePolicy>,
+    // Euclidean distance under which resources are duplicates. Defaults to 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_threshold: Option<f32>,
+    // Reduces the embeddings to fewer dimensions when they are indexed, and
+    // queries the same way. Not reduced by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reduction: Option<Reduction>,
+    // Scales the embeddings to unit length when they are indexed. Defaults to false.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normalize: Option<bool>,
+    // Precision the embeddings are stored with. Defaults to "f32".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub precision: Option<Precision>,
+}
+
+impl From<IndexOptions> for engine::IndexOptions {
+    fn from(options: IndexOptions) -> Self {
+        let default = engine::IndexOptions::default();
+
+        engine::IndexOptions {
+            bucket_size: options.bucket_size.unwrap_or(default.bucket_size),
+            index: match options.index {
+                Some(IndexWidth::U16) => engine::IndexWidth::U16,
+                Some(IndexWidth::U32) => engine::IndexWidth::U32,
+                None => default.index,
+            },
+            duplicates: match options.duplicates {
+                Some(DuplicatePolicy::Keep) => engine::DuplicatePolicy::Keep,
+                Some(DuplicatePolicy::Reject) => engine::DuplicatePolicy::Reject,
+                Some(DuplicatePolicy::Merge) => engine::DuplicatePolicy::Merge,
+                None => default.duplicates,
+            },
+            duplicate_threshold: options
+                .duplicate_threshold
+                .unwrap_or(default.duplicate_threshold),
+            reduction: options.reduction.map(|reduction| engine::Reduction {
+                dims: reduction.dims,
+                method: match reduction.method {
+                    ReductionMethod::Truncate => engine::ReductionMethod::Truncate,
+                    ReductionMethod::Pca => engine::ReductionMethod::Pca,
+                },
+            }),
+            normalize: options.normalize.unwrap_or(default.normalize),
+            precision: match options.precision {
+                Some(Precision::F32) => engine::Precision::F32,
+                Some(Precision::F16) => engine::Precision::F16,
+                Some(Precision::Bf16) => engine::Precision::Bf16,
+                None => default.precision,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct SegmentStats {
+    pub documents: usize,
+    pub tombstones: usize,
+    pub leaves: usize,
+    pub stems: usize,
+    pub min_depth: usize,
+    pub max_depth: usize,
+    pub bucket_fill: f32,
+    pub missing_from_tree: Vec<String>,
+    pub missing_from_data: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct IndexStats {
+    pub dimension: usize,
+    pub metric: String,
+    pub documents: usize,
+    pub tombstones: usize,
+    pub wal: usize,
+    pub segments: Vec<SegmentStats>,
+    pub memory_bytes: usize,
+    pub serialized_bytes: usize,
+}
+
+impl From<engine::SegmentStats> for SegmentStats {
+    fn from(stats: engine::SegmentStats) -> Self {
+        SegmentStats {
+            documents: stats.documents,
+            tombstones: stats.tombstones,
+            leaves: stats.leaves,
+            stems: stats.stems,
+            min_depth: stats.min_depth,
+            max_depth: stats.max_depth,
+            bucket_fill: stats.bucket_fill,
+            missing_from_tree: stats.missing_from_tree,
+            missing_from_data: stats.missing_from_data,
+        }
+    }
+}
+
+impl From<engine::Stats> for IndexStats {
+    fn from(stats: engine::Stats) -> Self {
+        IndexStats {
+            dimension: stats.dimension,
+            metric: stats.metric.to_owned(),
+            documents: stats.documents,
+            tombstones: stats.tombstones,
+            wal: stats.wal,
+            segments: stats.segments.into_iter().map(SegmentStats::from).collect(),
+            memory_bytes: stats.memory_bytes,
+            serialized_bytes: stats.serialized_bytes,
+        }
+    }
+}
+
+// Changes of an index since a version, as returned by "export_changes_since".
+// Removed resources have no embeddings.
+#[derive(Serialize, Deserialize, Debug, Clone, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct Delta {
+    pub since: u64,
+    pub version: u64,
+    pub cleared: bool,
+    pub added: Vec<EmbeddedResource>,
+    pub updated: Vec<EmbeddedResource>,
+    pub removed: Vec<EmbeddedResource>,
+}
+
+impl From<engine::Delta> for Delta {
+    fn from(delta: engine::Delta) -> Self {
+        let resources = |entries: Vec<(engine::Document, Vec<Vec<f32>>)>| {
+            entries
+                .iter()
+                .map(|(document, chunks)| EmbeddedResource::new(document, chunks))
+                .collect()
+        };
+
+        Delta {
+            since: delta.since,
+            version: delta.version,
+            cleared: delta.cleared,
+            added: resources(delta.added),
+            updated: resources(delta.updated),
+            removed: delta
+                .removed
+                .iter()
+                .map(|document| EmbeddedResource::new(document, &[]))
+                .collect(),
+        }
+    }
+}
+
+impl From<Delta> for engine::Delta {
+    fn from(delta: Delta) -> Self {
+        let entries = |resources: Vec<EmbeddedResource>| {
+            resources
+                .into_iter()
+                .map(|resource| {
+                    let (_, document, chunks) = engine::entry(resource);
+                    (document, chunks)
+                })
+                .collect()
+        };
+
+        engine::Delta {
+            since: delta.since,
+            version: delta.version,
+            cleared: delta.cleared,
+            added: entries(delta.added),
+            updated: entries(delta.updated),
+            removed: entries(delta.removed)
+                .into_iter()
+                .map(|(document, _)| document)
+                .collect(),
+        }
+    }
+}
@@ -1,69 +1,421 @@
-use crate::utils::set_panic_hook;
-use crate::{engine, Neighbor, NumberOfResult, Query, Resource, SearchResult, SerializedIndex};
+use crate::utils::{js_error, set_panic_hook, version_from_js, version_to_js};
+use crate::{
+    engine, BatchSearchResult, Clusters, Delta, Duplicates, EmbeddedResource, IndexOptions,
+    IndexStats, KnnGraph, MergeConflict, Neighbor, NumberOfResult, Projection, ProjectionMethod,
+    Queries, Query, Rejections, Resource, SearchOptions, SearchResult, SerializedIndex,
+    SerializedInput,
+};
 
+use js_sys::Uint8Array;
+use std::convert::TryInto;
 use wasm_bindgen::prelude::*;
 
+// Reads a serialized index from a string or from bytes, compressed or not.
+fn snapshot(serialized: SerializedInput) -> anyhow::Result<engine::Snapshot> {
+    let serialized: JsValue = serialized.into();
+    match serialized.as_string() {
+        Some(serialized) => engine::deserialize(&serialized),
+        None => engine::deserialize_bytes(&Uint8Array::new(&serialized).to_vec()),
+    }
+}
+
 #[wasm_bindgen]
 pub struct Voy {
-    index: engine::Index,
+    namespaces: engine::Namespaces,
+}
+
+impl Voy {
+    fn namespace(namespace: Option<String>) -> String {
+        namespace.unwrap_or_else(|| engine::DEFAULT_NAMESPACE.to_owned())
+    }
+
+    // Runs "f" on the index of the namespace, or on an empty index if it doesn't exist.
+    fn with_index<T>(&self, namespace: Option<String>, f: impl FnOnce(&engine::Index) -> T) -> T {
+        match self.namespaces.get(&Voy::namespace(namespace)) {
+            Some(index) => f(index),
+            None => f(&engine::Index::default()),
+        }
+    }
+
+    // Runs "f" on the index of the namespace, or on every index if none is given.
+    fn for_each_index(&mut self, namespace: Option<String>, f: impl Fn(&mut engine::Index)) {
+        match namespace {
+            Some(namespace) => self.namespaces.find_mut(&namespace).into_iter().for_each(f),
+            None => self.namespaces.indexes.values_mut().for_each(f),
+        }
+    }
+}
+
+impl From<engine::Index> for Voy {
+    fn from(index: engine::Index) -> Self {
+        Voy {
+            namespaces: engine::Namespaces::new(index),
+        }
+    }
 }
 
 #[wasm_bindgen]
 impl Voy {
     #[wasm_bindgen(constructor)]
-    pub fn new(resource: Option<Resource>) -> Voy {
+    pub fn new(resource: Option<Resource>, options: Option<IndexOptions>) -> Result<Voy, JsError> {
         set_panic_hook();
 
         let resource: Resource = match resource {
             Some(res) => res,
             _ => Resource { embeddings: vec![] },
         };
-        let index = engine::index(resource).unwrap();
-        Voy { index }
+        let options: engine::IndexOptions = options.unwrap_or_default().into();
+        let index = engine::index(resource, &options).map_err(js_error)?;
+        Ok(Voy::from(index))
     }
 
-    pub fn serialize(&self) -> SerializedIndex {
-        serde_json::to_string(&self.index).unwrap()
+    pub fn serialize(&self, namespace: Option<String>) -> Result<SerializedIndex, JsError> {
+        match namespace {
+            Some(namespace) => self.with_index(Some(namespace), engine::serialize_index),
+            None => engine::serialize_namespaces(&self.namespaces),
+        }
+        .map_err(js_error)
     }
 
-    pub fn deserialize(serialized_index: SerializedIndex) -> Voy {
-        let index: engine::Index = serde_json::from_str(&serialized_index).unwrap();
-        Voy { index }
+    // Compresses the serialized index. "deserialize" and "load" detect compressed
+    // indexes on their own.
+    #[cfg(feature = "compression")]
+    pub fn serialize_compressed(&self, namespace: Option<String>) -> Result<Vec<u8>, JsError> {
+        let serialized = self.serialize(namespace)?;
+        Ok(engine::compress(&serialized))
+    }
+
+    // Writes the namespace in the read-only format of FrozenIndex.
+    pub fn freeze(&self, namespace: Option<String>) -> Result<Vec<u8>, JsError> {
+        self.with_index(namespace, engine::freeze).map_err(js_error)
+    }
+
+    // Reads indexes serialized by any version of voy, migrating older formats.
+    pub fn deserialize(serialized_index: SerializedInput) -> Result<Voy, JsError> {
+        // An index serialized on its own is loaded into the default namespace.
+        let namespaces = match snapshot(serialized_index).map_err(js_error)? {
+            engine::Snapshot::Index(index) => engine::Namespaces::new(*index),
+            engine::Snapshot::Namespaces(namespaces) => namespaces,
+        };
+        Ok(Voy { namespaces })
     }
 
-    pub fn index(&mut self, resource: Resource) {
-        let index = engine::index(resource).unwrap();
-        self.index = index
+    pub fn load(
+        &mut self,
+        namespace: String,
+        serialized_index: SerializedInput,
+    ) -> Result<(), JsError> {
+        let index = snapshot(serialized_index)
+            .and_then(engine::Snapshot::into_index)
+            .map_err(js_error)?;
+        self.namespaces.insert(&namespace, index);
+        Ok(())
     }
 
-    pub fn search(&self, query: Query, k: NumberOfResult) -> SearchResult {
+    pub fn namespaces(&self) -> Vec<String> {
+        self.namespaces.names()
+    }
+
+    // Rebuilds the namespace with the options it was created with. Counts as a
+    // clear followed by an add for the version of the namespace.
+    pub fn index(&mut self, resource: Resource, namespace: Option<String>) -> Result<(), JsError> {
+        let index = self.namespaces.get_mut(&Voy::namespace(namespace));
+        engine::rebuild(index, resource).map_err(js_error)
+    }
+
+    pub fn search(
+        &self,
+        query: Query,
+        k: NumberOfResult,
+        options: Option<SearchOptions>,
+    ) -> Result<SearchResult, JsError> {
         let query: engine::Query = engine::Query::Embeddings(query);
-        let neighbors = engine::search(&self.index, &query, k).unwrap();
+        let options = options.unwrap_or_default();
+        let namespaces = match &options.namespaces {
+            Some(namespaces) => namespaces.to_owned(),
+            None => self.namespaces.names(),
+        };
+        let options: engine::SearchOptions = options.try_into().map_err(js_error)?;
+
+        let neighbors = self
+            .namespaces
+            .search(&namespaces, &query, k, &options)
+            .map_err(js_error)?;
         let neighbors: Vec<Neighbor> = neighbors
             .into_iter()
-            .map(|x| Neighbor {
-                id: x.id,
-                title: x.title,
-                url: x.url,
+            .map(|(namespace, neighbor)| Neighbor {
+                namespace: Some(namespace),
+                ..Neighbor::from(neighbor)
+            })
+            .collect();
+
+        Ok(SearchResult { neighbors })
+    }
+
+    // Searches several queries at once. With the "threads" feature, the queries
+    // are spread over the thread pool.
+    pub fn search_batch(
+        &self,
+        queries: Queries,
+        k: NumberOfResult,
+        options: Option<SearchOptions>,
+    ) -> Result<BatchSearchResult, JsError> {
+        let queries: Vec<engine::Query> = queries
+            .queries
+            .into_iter()
+            .map(engine::Query::Embeddings)
+            .collect();
+        let options = options.unwrap_or_default();
+        let namespaces = match &options.namespaces {
+            Some(namespaces) => namespaces.to_owned(),
+            None => self.namespaces.names(),
+        };
+        let options: engine::SearchOptions = options.try_into().map_err(js_error)?;
+
+        let results = self
+            .namespaces
+            .search_batch(&namespaces, &queries, k, &options)
+            .map_err(js_error)?;
+        let results: Vec<SearchResult> = results
+            .into_iter()
+            .map(|neighbors| SearchResult {
+                neighbors: neighbors
+                    .into_iter()
+                    .map(|(namespace, neighbor)| Neighbor {
+                        namespace: Some(namespace),
+                        ..Neighbor::from(neighbor)
+                    })
+                    .collect(),
             })
             .collect();
 
-        SearchResult { neighbors }
+        Ok(BatchSearchResult { results })
+    }
+
+    pub fn search_similar_to(
+        &self,
+        id: String,
+        k: NumberOfResult,
+        include_self: Option<bool>,
+        namespace: Option<String>,
+    ) -> Result<SearchResult, JsError> {
+        let include_self = include_self.unwrap_or(false);
+        let neighbors = self
+            .with_index(namespace, |index| {
+                engine::search_similar_to(index, &id, k, include_self)
+            })
+            .map_err(js_error)?;
+        let neighbors: Vec<Neighbor> = neighbors.into_iter().map(Neighbor::from).collect();
+
+        Ok(SearchResult { neighbors })
+    }
+
+    // Adds the valid resources. Those with invalid embeddings are left out and
+    // returned with the reason.
+    pub fn add(
+        &mut self,
+        resource: Resource,
+        namespace: Option<String>,
+    ) -> Result<Rejections, JsError> {
+        let index = self.namespaces.get_mut(&Voy::namespace(namespace));
+        let rejected = engine::add(index, &resource).map_err(js_error)?;
+        Ok(rejected.into())
+    }
+
+    pub fn remove(&mut self, resource: Resource, namespace: Option<String>) {
+        // Nothing to remove from a namespace that doesn't exist, so none is created.
+        if let Some(index) = self.namespaces.find_mut(&Voy::namespace(namespace)) {
+            engine::remove(index, &resource);
+        }
+    }
+
+    pub fn clear(&mut self, namespace: Option<String>) {
+        self.for_each_index(namespace, engine::clear);
+    }
+
+    // Merges every namespace of "other" into the namespace of the same name. The
+    // embeddings are reused, nothing is embedded again. Every namespace is merged
+    // before any is replaced, so a failed merge leaves this instance as it was.
+    pub fn merge(&mut self, other: &Voy, conflict: Option<MergeConflict>) -> Result<(), JsError> {
+        let conflict = conflict.map(Into::into).unwrap_or_default();
+
+        let mut merged: Vec<(&String, engine::Index)> = vec![];
+        for (name, other) in &other.namespaces.indexes {
+            let index = match self.namespaces.get(name) {
+                Some(index) => engine::merge(index, other, conflict)
+                    .map_err(|error| js_error(error.context(format!("namespace {}", name))))?,
+                None => other.clone(),
+            };
+            merged.push((name, index));
+        }
+
+        for (name, index) in merged {
+            engine::replace(self.namespaces.get_mut(name), index);
+        }
+
+        Ok(())
+    }
+
+    // Version of the namespace, bumped by every change of its resources.
+    pub fn version(&self, namespace: Option<String>) -> f64 {
+        self.with_index(namespace, |index| version_to_js(index.changes.version))
+    }
+
+    // Resources added, updated and removed since the namespace was at "version".
+    pub fn export_changes_since(
+        &self,
+        version: f64,
+        namespace: Option<String>,
+    ) -> Result<Delta, JsError> {
+        let version = version_from_js(version)?;
+        Ok(self.with_index(namespace, |index| {
+            engine::export_changes_since(index, version).into()
+        }))
+    }
+
+    pub fn apply_changes(
+        &mut self,
+        delta: Delta,
+        namespace: Option<String>,
+    ) -> Result<(), JsError> {
+        let index = self.namespaces.get_mut(&Voy::namespace(namespace));
+        engine::apply_changes(index, &delta.into()).map_err(js_error)
+    }
+
+    pub fn compact(&mut self, namespace: Option<String>) -> Result<(), JsError> {
+        match namespace {
+            Some(namespace) => self
+                .namespaces
+                .find_mut(&namespace)
+                .map_or(Ok(()), engine::compact),
+            None => self
+                .namespaces
+                .indexes
+                .values_mut()
+                .try_for_each(engine::compact),
+        }
+        .map_err(js_error)
+    }
+
+    pub fn size(&self, namespace: Option<String>) -> usize {
+        match namespace {
+            Some(namespace) => self.with_index(Some(namespace), engine::size),
+            None => self.namespaces.indexes.values().map(engine::size).sum(),
+        }
+    }
+
+    pub fn get(&self, id: String, namespace: Option<String>) -> Option<EmbeddedResource> {
+        self.with_index(namespace, |index| {
+            engine::find(index, &id)
+                .map(|(document, embeddings)| EmbeddedResource::new(document, &embeddings.decode()))
+        })
+    }
+
+    pub fn has(&self, id: String, namespace: Option<String>) -> bool {
+        self.with_index(namespace, |index| engine::find(index, &id).is_some())
+    }
+
+    pub fn ids(&self, namespace: Option<String>) -> Vec<String> {
+        self.with_index(namespace, |index| {
+            engine::documents(index, 0, usize::MAX)
+                .into_iter()
+                .map(|(document, _)| document.id.to_owned())
+                .collect()
+        })
+    }
+
+    pub fn documents(
+        &self,
+        offset: usize,
+        limit: usize,
+        include_embeddings: Option<bool>,
+        namespace: Option<String>,
+    ) -> Resource {
+        let include_embeddings = include_embeddings.unwrap_or(false);
+        let embeddings = self.with_index(namespace, |index| {
+            engine::documents(index, offset, limit)
+                .into_iter()
+                .map(|(document, embeddings)| {
+                    if include_embeddings {
+                        EmbeddedResource::new(document, &embeddings.decode())
+                    } else {
+                        EmbeddedResource::new(document, &[])
+                    }
+                })
+                .collect()
+        });
+
+        Resource { embeddings }
+    }
+
+    pub fn stats(&self, namespace: Option<String>) -> Result<IndexStats, JsError> {
+        let stats = self
+            .with_index(namespace, engine::stats)
+            .map_err(js_error)?;
+        Ok(stats.into())
+    }
+
+    // Groups the resources of the namespace into k clusters with k-means. The same
+    // seed gives the same clusters.
+    pub fn cluster(
+        &self,
+        k: usize,
+        iterations: usize,
+        seed: u32,
+        namespace: Option<String>,
+    ) -> Result<Clusters, JsError> {
+        self.with_index(namespace, |index| {
+            engine::cluster(index, k, iterations, seed as u64).map(Clusters::from)
+        })
+        .map_err(js_error)
     }
 
-    pub fn add(&mut self, resource: Resource) {
-        engine::add(&mut self.index, &resource);
+    // The k nearest resources of every resource of the namespace. Only edges
+    // shorter than "threshold" are kept when it's given.
+    pub fn knn_graph(
+        &self,
+        k: usize,
+        threshold: Option<f32>,
+        namespace: Option<String>,
+    ) -> KnnGraph {
+        self.with_index(namespace, |index| {
+            engine::knn_graph(index, k, threshold).into()
+        })
     }
 
-    pub fn remove(&mut self, resource: Resource) {
-        engine::remove(&mut self.index, &resource);
+    // Projects the resources of the namespace to "dims" dimensions. The projection
+    // also maps queries into the same space.
+    pub fn project(
+        &self,
+        dims: usize,
+        method: Option<ProjectionMethod>,
+        seed: Option<u32>,
+        namespace: Option<String>,
+    ) -> Result<Projection, JsError> {
+        self.with_index(namespace, |index| Projection::of(index, dims, method, seed))
     }
 
-    pub fn clear(&mut self) {
-        engine::clear(&mut self.index);
+    // Groups of resources within "threshold", in euclidean distance, of each other.
+    pub fn find_duplicates(&self, threshold: f32, namespace: Option<String>) -> Duplicates {
+        self.with_index(namespace, |index| {
+            engine::find_duplicates(index, threshold).into()
+        })
     }
 
-    pub fn size(&self) -> usize {
-        engine::size(&self.index)
+    // Checks the index of the namespace, or of every namespace if none is given,
+    // for inconsistencies between the k-d trees and the stored documents.
+    pub fn verify(&self, namespace: Option<String>) -> Result<(), JsError> {
+        match namespace {
+            Some(namespace) => self.with_index(Some(namespace), engine::verify),
+            None => self
+                .namespaces
+                .indexes
+                .iter()
+                .try_for_each(|(name, index)| {
+                    engine::verify(index)
+                        .map_err(|error| error.context(format!("namespace {}", name)))
+                }),
+        }
+        .map_err(js_error)
     }
 }